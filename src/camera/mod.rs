@@ -1,6 +1,8 @@
 use bevy::prelude::Res;
 use bevy::prelude::Input;
 use bevy::prelude::KeyCode;
+use bevy::prelude::{GlobalTransform, Vec2, Vec3, Vec4, Windows};
+use bevy::render::camera::Camera;
 
 pub mod third_person_camera;
 
@@ -17,4 +19,27 @@ fn movement_axis(
 		axis -= 1.0;
 	}
 	axis
-}
\ No newline at end of file
+}
+/// World space origin and direction of the ray under the mouse cursor.
+pub fn cursor_ray(
+	windows: &Windows,
+	camera: &Camera,
+	transform: &GlobalTransform,
+) -> Option<(Vec3, Vec3)> {
+	let window = windows.get(camera.window)?;
+	let cursor = window.cursor_position()?;
+	let ndc = Vec2::new(
+		cursor.x / window.width() as f32 * 2.0 - 1.0,
+		cursor.y / window.height() as f32 * 2.0 - 1.0,
+	);
+
+	let ndc_to_world = transform.compute_matrix() * camera.projection_matrix.inverse();
+	let unproject = |depth: f32| {
+		let point = ndc_to_world * Vec4::new(ndc.x, ndc.y, depth, 1.0);
+		point.truncate() / point.w
+	};
+
+	let near = unproject(0.0);
+	let far = unproject(1.0);
+	Some((near, (far - near).normalize()))
+}
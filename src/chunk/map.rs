@@ -0,0 +1,164 @@
+use std::collections::HashMap;
+
+use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use super::ChunkSettings;
+
+/// Integer position of a chunk in the chunk grid. Neighbouring chunks share
+/// their border samples on all six faces, so chunks are `width - 1`,
+/// `height - 1` and `length - 1` units apart.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct ChunkCoord {
+    pub x: i32,
+    pub y: i32,
+    pub z: i32,
+}
+
+impl ChunkCoord {
+    pub fn new(x: i32, y: i32, z: i32) -> Self {
+        ChunkCoord { x, y, z }
+    }
+
+    pub fn from_world(chunk_settings: &ChunkSettings, position: Vec3) -> Self {
+        ChunkCoord {
+            x: (position.x / (chunk_settings.width - 1) as f32).floor() as i32,
+            y: (position.y / (chunk_settings.height - 1) as f32).floor() as i32,
+            z: (position.z / (chunk_settings.length - 1) as f32).floor() as i32,
+        }
+    }
+
+    /// World position of the chunk's first sample.
+    pub fn origin(&self, chunk_settings: &ChunkSettings) -> Vec3 {
+        Vec3::new(
+            (self.x * (chunk_settings.width - 1) as i32) as f32,
+            (self.y * (chunk_settings.height - 1) as i32) as f32,
+            (self.z * (chunk_settings.length - 1) as i32) as f32,
+        )
+    }
+
+    pub fn distance_squared(&self, other: ChunkCoord) -> i32 {
+        (self.x - other.x) * (self.x - other.x)
+            + (self.y - other.y) * (self.y - other.y)
+            + (self.z - other.z) * (self.z - other.z)
+    }
+
+    /// The 26 chunks sharing a face, edge or corner with this one.
+    pub fn neighbours(&self) -> [ChunkCoord; 26] {
+        let mut neighbours = [*self; 26];
+        let mut i = 0;
+        for y in -1..=1 {
+            for z in -1..=1 {
+                for x in -1..=1 {
+                    if x == 0 && y == 0 && z == 0 {
+                        continue;
+                    }
+                    neighbours[i] = ChunkCoord::new(self.x + x, self.y + y, self.z + z);
+                    i += 1;
+                }
+            }
+        }
+        neighbours
+    }
+}
+
+/// Converts a world position to the chunk containing it and the index of
+/// the nearest sample inside that chunk.
+pub fn world_to_voxel(chunk_settings: &ChunkSettings, position: Vec3) -> (ChunkCoord, [usize; 3]) {
+    let voxel_x = position.x.round() as i32;
+    let voxel_y = position.y.round() as i32;
+    let voxel_z = position.z.round() as i32;
+
+    let step_x = (chunk_settings.width - 1) as i32;
+    let step_y = (chunk_settings.height - 1) as i32;
+    let step_z = (chunk_settings.length - 1) as i32;
+    let coord = ChunkCoord::new(
+        voxel_x.div_euclid(step_x),
+        voxel_y.div_euclid(step_y),
+        voxel_z.div_euclid(step_z),
+    );
+
+    (
+        coord,
+        [
+            voxel_x.rem_euclid(step_x) as usize,
+            voxel_y.rem_euclid(step_y) as usize,
+            voxel_z.rem_euclid(step_z) as usize,
+        ],
+    )
+}
+
+/// Every loaded chunk by its coordinate.
+#[derive(Default)]
+pub struct ChunkMap {
+    chunks: HashMap<ChunkCoord, Entity>,
+}
+
+impl ChunkMap {
+    pub fn get(&self, coord: ChunkCoord) -> Option<Entity> {
+        self.chunks.get(&coord).copied()
+    }
+
+    pub fn contains(&self, coord: ChunkCoord) -> bool {
+        self.chunks.contains_key(&coord)
+    }
+
+    pub fn insert(&mut self, coord: ChunkCoord, entity: Entity) {
+        self.chunks.insert(coord, entity);
+    }
+
+    pub fn remove(&mut self, coord: ChunkCoord) -> Option<Entity> {
+        self.chunks.remove(&coord)
+    }
+
+    pub fn len(&self) -> usize {
+        self.chunks.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.chunks.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (ChunkCoord, Entity)> + '_ {
+        self.chunks.iter().map(|(coord, entity)| (*coord, *entity))
+    }
+
+    pub fn chunk_at(&self, chunk_settings: &ChunkSettings, position: Vec3) -> Option<Entity> {
+        self.get(ChunkCoord::from_world(chunk_settings, position))
+    }
+
+    /// Loaded chunks sharing a face, edge or corner with `coord`.
+    pub fn neighbours(&self, coord: ChunkCoord) -> impl Iterator<Item = (ChunkCoord, Entity)> + '_ {
+        let neighbours = coord.neighbours();
+        (0..neighbours.len()).filter_map(move |i| self.get(neighbours[i]).map(|entity| (neighbours[i], entity)))
+    }
+
+    /// Loaded chunks whose samples overlap the world space box. A box touching
+    /// a shared border returns the chunks on both sides of it.
+    pub fn chunks_in_aabb(&self, chunk_settings: &ChunkSettings, min: Vec3, max: Vec3) -> Vec<(ChunkCoord, Entity)> {
+        let step_x = (chunk_settings.width - 1) as f32;
+        let step_y = (chunk_settings.height - 1) as f32;
+        let step_z = (chunk_settings.length - 1) as f32;
+
+        let min_x = ((min.x / step_x).ceil() as i32) - 1;
+        let min_y = ((min.y / step_y).ceil() as i32) - 1;
+        let min_z = ((min.z / step_z).ceil() as i32) - 1;
+        let max_x = (max.x / step_x).floor() as i32;
+        let max_y = (max.y / step_y).floor() as i32;
+        let max_z = (max.z / step_z).floor() as i32;
+
+        let mut chunks = Vec::new();
+        for y in min_y..=max_y {
+            for z in min_z..=max_z {
+                for x in min_x..=max_x {
+                    let coord = ChunkCoord::new(x, y, z);
+                    if let Some(entity) = self.get(coord) {
+                        chunks.push((coord, entity));
+                    }
+                }
+            }
+        }
+
+        chunks
+    }
+}
@@ -5,39 +5,77 @@ use bevy::render::mesh::Mesh;
 use bevy::render::mesh::VertexAttributeValues;
 use bevy::render::{pipeline::PrimitiveTopology, render_graph::base::MainPass};
 use bevy::{asset::Assets, ecs::Query, prelude::Handle};
-use bevy_mod_picking::InteractableMesh;
-use bevy_mod_picking::PickableMesh;
 use bevy_rapier3d::{
-    physics::{ColliderHandleComponent, RigidBodyHandleComponent},
-    rapier::{
-        dynamics::RigidBodySet,
-        geometry::{ColliderBuilder, ColliderSet},
-        math::{Point, Real},
-    },
+    physics::RigidBodyHandleComponent,
+    rapier::{geometry::ColliderBuilder, math::Real},
 };
+use bevy::render::pipeline::RenderPipeline;
+use bevy_rapier3d::rapier::dynamics::RigidBodyBuilder;
 use pipeline::setup_marching_mesh_pipeline;
+use pipeline::ChunkRenderAssets;
 use pipeline::MarchMeshMaterial;
-use pipeline::ATTRIBUTE_POINT_DATA;
+use pipeline::{ATTRIBUTE_MATERIAL, ATTRIBUTE_POINT_DATA};
 use stage::POST_UPDATE;
+use map::{ChunkCoord, ChunkMap};
+use physics::{chunk_collider, rebuild_chunk_colliders, ColliderRebuildSettings, ColliderRebuilds, PhysicsMeshSettings};
+use raycast::{update_terrain_cursor, RaycastSettings, TerrainCursor};
+use streaming::{stream_chunks, StreamingSettings};
 
 use crate::triangulation::{self, triangulation};
 
+pub mod map;
+pub mod physics;
 pub mod pipeline;
+pub mod raycast;
+pub mod streaming;
+pub mod terrain;
+#[cfg(test)]
+pub mod test_util;
 
 #[derive(Clone)]
 pub struct Chunk {
     pub data: Box<Vec<Vec<Vec<f32>>>>,
+    /// Material of every sample, indexed like `data`. 0 is the default
+    /// ground material.
+    pub materials: Box<Vec<Vec<Vec<u8>>>>,
 }
 
 impl Default for Chunk {
     fn default() -> Self {
         Chunk {
             data: Box::new(vec![vec![Vec::new()]]),
+            materials: Box::new(vec![vec![Vec::new()]]),
         }
     }
 }
 
-#[derive(Default)]
+impl Chunk {
+    pub fn new(chunk_settings: &ChunkSettings) -> Self {
+        Chunk {
+            data: Box::new(vec![
+                vec![vec![chunk_settings.threshold; chunk_settings.length]; chunk_settings.height];
+                chunk_settings.width
+            ]),
+            materials: Box::new(vec![
+                vec![vec![0; chunk_settings.length]; chunk_settings.height];
+                chunk_settings.width
+            ]),
+        }
+    }
+}
+
+/// Sent when whole chunks were rewritten outside of a sculpting edit, e.g.
+/// by undo or by regenerating them.
+#[derive(Clone, Debug)]
+pub struct ChunksRewritten {
+    pub chunks: Vec<ChunkCoord>,
+    /// The chunks hold nothing but generator output plus the edit journal,
+    /// so they can be rebuilt. Their saved copies are dropped rather than
+    /// rewritten.
+    pub regenerated: bool,
+}
+
+#[derive(Default, Clone, Copy)]
 pub struct ChunkSettings {
     pub length: usize,
     pub width: usize,
@@ -57,76 +95,91 @@ pub struct MarchingChunkBundle {
     pub global_transform: GlobalTransform,
 }
 
+/// Collision group every chunk collider is a member of.
+pub const TERRAIN_COLLISION_GROUP: u16 = 0b0000_0001;
+
+/// Fills `mesh` with the chunk's surface.
+pub fn build_chunk_mesh(mesh: &mut Mesh, chunk_settings: &ChunkSettings, chunk: &Chunk) {
+    let (v_pos, normals, p_data, materials, indices) = generate_mesh(chunk_settings, chunk);
+
+    mesh.set_attribute(
+        Mesh::ATTRIBUTE_POSITION,
+        VertexAttributeValues::Float3(v_pos),
+    );
+
+    mesh.set_attribute(
+        Mesh::ATTRIBUTE_NORMAL,
+        VertexAttributeValues::Float3(normals),
+    );
+
+    mesh.set_attribute(ATTRIBUTE_POINT_DATA, VertexAttributeValues::Float(p_data));
+
+    mesh.set_attribute(ATTRIBUTE_MATERIAL, VertexAttributeValues::Float(materials));
+
+    mesh.set_indices(Some(bevy::render::mesh::Indices::U32(indices)));
+}
+
+/// Spawns a chunk already meshed, with its collider built from the same
+/// densities so physics sees the surface from the first frame.
+pub fn spawn_chunk(
+    commands: &mut Commands,
+    meshes: &mut Assets<Mesh>,
+    render_assets: &ChunkRenderAssets,
+    chunk_settings: &ChunkSettings,
+    physics_settings: &PhysicsMeshSettings,
+    coord: ChunkCoord,
+    chunk: Chunk,
+) -> Entity {
+    let origin = coord.origin(chunk_settings);
+    let mut mesh = Mesh::new(PrimitiveTopology::TriangleList);
+    build_chunk_mesh(&mut mesh, chunk_settings, &chunk);
+    let collider = chunk_collider(physics_settings.mode, chunk_settings, &chunk);
+
+    commands
+        .spawn(MarchingChunkBundle {
+            mesh: meshes.add(mesh),
+            chunk,
+            render_pipelines: RenderPipelines::from_pipelines(vec![RenderPipeline::new(
+                render_assets.pipeline.clone_weak(),
+            )]),
+            transform: Transform::from_translation(origin),
+            ..Default::default()
+        })
+        .with(render_assets.material.clone_weak())
+        .with(coord)
+        .with(RigidBodyBuilder::new_static().translation(origin.x as Real, origin.y as Real, origin.z as Real));
+
+    // Empty chunks get no collider at all
+    if let Some(collider) = collider {
+        commands.with(collider);
+    }
+
+    commands.current_entity().unwrap()
+}
+
+/// Remeshes changed chunks right away and queues their colliders, which are
+/// rebuilt in the background once the chunk stops changing.
 fn regen_mesh(
     commands: &mut Commands,
     mut meshes: ResMut<Assets<Mesh>>,
-    mut collider_set: ResMut<ColliderSet>,
-    mut bodies: ResMut<RigidBodySet>,
+    mut collider_rebuilds: ResMut<ColliderRebuilds>,
     chunk_settings: Res<ChunkSettings>,
-    mesh_query: Query<
-        (
-            &Chunk,
-            &Handle<Mesh>,
-            &ColliderHandleComponent,
-            &RigidBodyHandleComponent,
-            Entity,
-        ),
-        Changed<Chunk>,
-    >,
-    interactable_query: Query<(&PickableMesh, &InteractableMesh)>,
+    physics_settings: Res<PhysicsMeshSettings>,
+    mesh_query: Query<(&Chunk, &Handle<Mesh>, Option<&RigidBodyHandleComponent>, Entity), Mutated<Chunk>>,
 ) {
-    for (chunk, mesh_handle, collider_handle, rigid_body_handle, entity) in mesh_query.iter() {
+    for (chunk, mesh_handle, rigid_body_handle, entity) in mesh_query.iter() {
         let mesh = meshes.get_mut(mesh_handle).unwrap();
-        let (v_pos, normals, p_data, indices) = generate_mesh(&chunk_settings, &chunk);
-        let mut collider_verts: Vec<Point<Real>> = Vec::new();
-        let mut collider_indicies: Vec<[u32; 3]> = Vec::new();
-
-        for pos in v_pos.iter() {
-            collider_verts.push(Point::new(pos[0], pos[1], pos[2]));
-        }
-
-        for i in (0..indices.len()).step_by(3) {
-            collider_indicies.push([indices[i], indices[i + 1], indices[i + 2]]);
-        }
-
-        collider_set.remove(collider_handle.handle(), &mut bodies, false);
-        let new_handle = collider_set.insert(
-            ColliderBuilder::trimesh(
-                collider_verts, 
-                collider_indicies
-            ).build(),
-            rigid_body_handle.handle(),
-            &mut bodies,
-        );
-        commands.remove_one::<ColliderHandleComponent>(entity);
-        commands.set_current_entity(entity);
-        commands.with(ColliderHandleComponent::from(new_handle));
-
-        mesh.set_attribute(
-            Mesh::ATTRIBUTE_POSITION,
-            VertexAttributeValues::Float3(v_pos),
-        );
-
-        mesh.set_attribute(
-            Mesh::ATTRIBUTE_NORMAL,
-            VertexAttributeValues::Float3(normals),
-        );
-
-        mesh.set_attribute(ATTRIBUTE_POINT_DATA, VertexAttributeValues::Float(p_data));
-
-        mesh.set_indices(Some(bevy::render::mesh::Indices::U32(indices)));
-
-        let interactable_query_result = interactable_query.get(entity);
-
-        match interactable_query_result {
-            Ok(_) => {}
-            Err(query_error) => match query_error {
-                bevy::ecs::QueryError::NoSuchEntity => {
-                    commands.insert_one(entity, PickableMesh::default());
-                    commands.insert_one(entity, InteractableMesh::default());
-                }
-                _ => {}
-            },
+        build_chunk_mesh(mesh, &chunk_settings, &chunk);
+
+        if rigid_body_handle.is_some() {
+            collider_rebuilds.queue(entity, chunk.clone());
+        } else {
+            // Physics hasn't built the body yet, swap the builder it will
+            // use instead
+            commands.remove_one::<ColliderBuilder>(entity);
+            if let Some(collider) = chunk_collider(physics_settings.mode, &chunk_settings, &chunk) {
+                commands.insert_one(entity, collider);
+            }
         }
     }
 }
@@ -138,10 +191,11 @@ pub fn normalize_f32(value: f32, min: f32, max: f32) -> f32 {
 pub fn generate_mesh(
     chunk_settings: &ChunkSettings,
     chunk: &Chunk,
-) -> (Vec<[f32; 3]>, Vec<[f32; 3]>, Vec<f32>, Vec<u32>) {
+) -> (Vec<[f32; 3]>, Vec<[f32; 3]>, Vec<f32>, Vec<f32>, Vec<u32>) {
     let mut v_pos: Vec<[f32; 3]> = Vec::new();
     let mut normals: Vec<[f32; 3]> = Vec::new();
     let mut p_data: Vec<f32> = Vec::new();
+    let mut materials: Vec<f32> = Vec::new();
     let mut indices: Vec<u32> = Vec::new();
 
     for y in 0..chunk_settings.height - 1 {
@@ -173,6 +227,17 @@ pub fn generate_mesh(
                     chunk.data[x][y + 1][z],         // 7
                 ];
 
+                let point_materials = [
+                    chunk.materials[x][y][z + 1],
+                    chunk.materials[x + 1][y][z + 1],
+                    chunk.materials[x + 1][y][z],
+                    chunk.materials[x][y][z],
+                    chunk.materials[x][y + 1][z + 1],
+                    chunk.materials[x + 1][y + 1][z + 1],
+                    chunk.materials[x + 1][y + 1][z],
+                    chunk.materials[x][y + 1][z],
+                ];
+
                 let mut cube_ndex: usize = 0;
                 for i in 0..8 {
                     if point_data[i] > chunk_settings.threshold {
@@ -216,6 +281,12 @@ pub fn generate_mesh(
                         // v_pos.push(pos);
                         tri_verts.push(pos);
                         p_data.push(point_a_data);
+                        // Vertices take the material of the solid corner
+                        materials.push(if point_a_data > point_b_data {
+                            point_materials[index_b]
+                        } else {
+                            point_materials[index_a]
+                        } as f32);
                         indices.push(p_data.len() as u32 - 1);
                     }
                 }
@@ -238,7 +309,7 @@ pub fn generate_mesh(
         }
     }
 
-    return (v_pos, normals, p_data, indices);
+    return (v_pos, normals, p_data, materials, indices);
 }
 
 pub struct MarchingCubesPlugin;
@@ -247,12 +318,23 @@ impl Plugin for MarchingCubesPlugin {
         app.add_resource(ChunkSettings {
             length: 16,
             width: 16,
-            height: 40,
+            height: 16,
             threshold: 0.0,
             ..Default::default()
         })
+        .add_event::<ChunksRewritten>()
+        .init_resource::<StreamingSettings>()
+        .init_resource::<ChunkMap>()
+        .init_resource::<RaycastSettings>()
+        .init_resource::<TerrainCursor>()
+        .init_resource::<ColliderRebuildSettings>()
+        .init_resource::<ColliderRebuilds>()
+        .init_resource::<PhysicsMeshSettings>()
         .add_startup_system(setup_marching_mesh_pipeline.system())
         .add_asset::<MarchMeshMaterial>()
-        .add_system_to_stage(POST_UPDATE, regen_mesh.system());
+        .add_system(stream_chunks.system())
+        .add_system_to_stage(stage::PRE_UPDATE, update_terrain_cursor.system())
+        .add_system_to_stage(POST_UPDATE, regen_mesh.system())
+        .add_system_to_stage(POST_UPDATE, rebuild_chunk_colliders.system());
     }
 }
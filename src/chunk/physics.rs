@@ -0,0 +1,346 @@
+use std::cmp::Ordering;
+use std::collections::HashMap;
+
+use bevy::prelude::*;
+use bevy::tasks::{AsyncComputeTaskPool, Task};
+use bevy_rapier3d::{
+    physics::{ColliderHandleComponent, RigidBodyHandleComponent},
+    rapier::{
+        dynamics::RigidBodySet,
+        geometry::{Collider, ColliderBuilder, ColliderSet, InteractionGroups},
+        math::{Point, Real, Vector},
+        na::DMatrix,
+    },
+};
+use futures_lite::future;
+
+use super::{generate_mesh, Chunk, ChunkSettings, TERRAIN_COLLISION_GROUP};
+
+pub struct ColliderRebuildSettings {
+    /// Seconds a chunk has to stay unchanged before its collider is rebuilt,
+    /// so sculpting doesn't rebuild it every frame.
+    pub debounce: f32,
+    /// Upper bound on collider builds started per frame.
+    pub max_per_frame: usize,
+}
+
+impl Default for ColliderRebuildSettings {
+    fn default() -> Self {
+        Self {
+            debounce: 0.2,
+            max_per_frame: 4,
+        }
+    }
+}
+
+/// How a chunk's collider is built from its densities. Physics doesn't need
+/// the render mesh's resolution, the coarser shapes keep rapier's memory and
+/// collider rebuilds down.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PhysicsMeshMode {
+    /// Trimesh of the full resolution surface, same as the render mesh.
+    Full,
+    /// Trimesh of the surface marched over every `stride`th sample.
+    Stride(usize),
+    /// Heightfield for chunks without overhangs, the full surface otherwise.
+    Heightfield,
+    /// Heightfield where possible, otherwise the surface marched at `stride`.
+    Auto { stride: usize },
+}
+
+pub struct PhysicsMeshSettings {
+    pub mode: PhysicsMeshMode,
+}
+
+impl Default for PhysicsMeshSettings {
+    fn default() -> Self {
+        Self {
+            mode: PhysicsMeshMode::Auto { stride: 2 },
+        }
+    }
+}
+
+/// Collider for a chunk, `None` if it has no surface.
+pub fn chunk_collider(mode: PhysicsMeshMode, chunk_settings: &ChunkSettings, chunk: &Chunk) -> Option<ColliderBuilder> {
+    let collider = match mode {
+        PhysicsMeshMode::Full => full_collider(chunk_settings, chunk),
+        PhysicsMeshMode::Stride(stride) => stride_collider(chunk_settings, chunk, stride),
+        PhysicsMeshMode::Heightfield => {
+            heightfield_collider(chunk_settings, chunk).or_else(|| full_collider(chunk_settings, chunk))
+        }
+        PhysicsMeshMode::Auto { stride } => {
+            heightfield_collider(chunk_settings, chunk).or_else(|| stride_collider(chunk_settings, chunk, stride))
+        }
+    };
+
+    collider.map(|collider| collider.collision_groups(InteractionGroups::new(TERRAIN_COLLISION_GROUP, u16::MAX)))
+}
+
+fn trimesh(vertices: &[[f32; 3]], indices: &[u32]) -> Option<ColliderBuilder> {
+    if indices.is_empty() {
+        return None;
+    }
+
+    let collider_verts: Vec<Point<Real>> = vertices.iter().map(|pos| Point::new(pos[0], pos[1], pos[2])).collect();
+    let collider_indicies: Vec<[u32; 3]> = indices
+        .chunks_exact(3)
+        .map(|triangle| [triangle[0], triangle[1], triangle[2]])
+        .collect();
+
+    Some(ColliderBuilder::trimesh(collider_verts, collider_indicies))
+}
+
+fn full_collider(chunk_settings: &ChunkSettings, chunk: &Chunk) -> Option<ColliderBuilder> {
+    let (v_pos, _, _, _, indices) = generate_mesh(chunk_settings, chunk);
+    trimesh(&v_pos, &indices)
+}
+
+/// Every `stride`th sample index below `size`, always ending on the last one
+/// so the coarse surface still meets the neighbouring chunks.
+fn stride_samples(size: usize, stride: usize) -> Vec<usize> {
+    let mut samples: Vec<usize> = (0..size).step_by(stride).collect();
+    if samples.last() != Some(&(size - 1)) {
+        samples.push(size - 1);
+    }
+    samples
+}
+
+/// Maps a position on the coarse grid back onto the chunk's samples.
+fn from_coarse(samples: &[usize], coarse: f32) -> f32 {
+    let index = (coarse.floor().max(0.0) as usize).min(samples.len() - 2);
+    let t = coarse - index as f32;
+    samples[index] as f32 + t * (samples[index + 1] - samples[index]) as f32
+}
+
+fn stride_collider(chunk_settings: &ChunkSettings, chunk: &Chunk, stride: usize) -> Option<ColliderBuilder> {
+    if stride <= 1 {
+        return full_collider(chunk_settings, chunk);
+    }
+
+    let xs = stride_samples(chunk_settings.width, stride);
+    let ys = stride_samples(chunk_settings.height, stride);
+    let zs = stride_samples(chunk_settings.length, stride);
+
+    let coarse_settings = ChunkSettings {
+        width: xs.len(),
+        height: ys.len(),
+        length: zs.len(),
+        threshold: chunk_settings.threshold,
+    };
+    let coarse = Chunk {
+        data: Box::new(
+            xs.iter()
+                .map(|&x| {
+                    ys.iter()
+                        .map(|&y| zs.iter().map(|&z| chunk.data[x][y][z]).collect())
+                        .collect()
+                })
+                .collect(),
+        ),
+        materials: Box::new(vec![vec![vec![0; zs.len()]; ys.len()]; xs.len()]),
+    };
+
+    let (mut v_pos, _, _, _, indices) = generate_mesh(&coarse_settings, &coarse);
+    for pos in v_pos.iter_mut() {
+        *pos = [
+            from_coarse(&xs, pos[0]),
+            from_coarse(&ys, pos[1]),
+            from_coarse(&zs, pos[2]),
+        ];
+    }
+
+    trimesh(&v_pos, &indices)
+}
+
+/// Heightfield of the surface, `None` unless every column of the chunk goes
+/// from solid to air exactly once.
+fn heightfield_collider(chunk_settings: &ChunkSettings, chunk: &Chunk) -> Option<ColliderBuilder> {
+    let threshold = chunk_settings.threshold;
+    let solid = |density: f32| density <= threshold;
+    let mut heights = DMatrix::zeros(chunk_settings.length, chunk_settings.width);
+
+    for x in 0..chunk_settings.width {
+        for z in 0..chunk_settings.length {
+            let column = &chunk.data[x];
+            // Everything up to the first air sample is solid, so only the
+            // bottom one and the ones above the crossing need checking
+            let crossing = (0..chunk_settings.height - 1).find(|&y| !solid(column[y + 1][z]))?;
+            if !solid(column[0][z]) || (crossing + 1..chunk_settings.height).any(|y| solid(column[y][z])) {
+                return None;
+            }
+
+            let below = column[crossing][z];
+            let above = column[crossing + 1][z];
+            heights[(z, x)] = crossing as Real + (threshold - below) / (above - below);
+        }
+    }
+
+    // Heightfields are centered on their origin, chunks start at theirs
+    let extent_x = (chunk_settings.width - 1) as Real;
+    let extent_z = (chunk_settings.length - 1) as Real;
+    Some(
+        ColliderBuilder::heightfield(heights, Vector::new(extent_x, 1.0, extent_z)).translation(
+            extent_x / 2.0,
+            0.0,
+            extent_z / 2.0,
+        ),
+    )
+}
+
+struct PendingCollider {
+    /// Seconds since the chunk last changed.
+    quiet: f32,
+    chunk: Chunk,
+}
+
+/// Chunk colliders waiting for their surface to settle and the ones being
+/// built on the compute pool. The tasks build the collider itself, the
+/// main thread only swaps it in.
+#[derive(Default)]
+pub struct ColliderRebuilds {
+    pending: HashMap<Entity, PendingCollider>,
+    building: HashMap<Entity, Task<Option<Collider>>>,
+}
+
+impl ColliderRebuilds {
+    /// Queues a rebuild from the chunk's new densities, restarting its
+    /// debounce.
+    pub fn queue(&mut self, entity: Entity, chunk: Chunk) {
+        self.pending.insert(entity, PendingCollider { quiet: 0.0, chunk });
+    }
+}
+
+/// Swaps in finished colliders, then starts builds for the settled chunks
+/// closest to a dynamic body.
+#[allow(clippy::too_many_arguments)] // Bevy systems take their resources as parameters
+pub fn rebuild_chunk_colliders(
+    commands: &mut Commands,
+    time: Res<Time>,
+    settings: Res<ColliderRebuildSettings>,
+    physics_settings: Res<PhysicsMeshSettings>,
+    chunk_settings: Res<ChunkSettings>,
+    task_pool: Res<AsyncComputeTaskPool>,
+    mut rebuilds: ResMut<ColliderRebuilds>,
+    mut collider_set: ResMut<ColliderSet>,
+    mut bodies: ResMut<RigidBodySet>,
+    chunk_query: Query<(&GlobalTransform, &RigidBodyHandleComponent, Option<&ColliderHandleComponent>), With<Chunk>>,
+    body_query: Query<(&GlobalTransform, &RigidBodyHandleComponent), Without<Chunk>>,
+) {
+    let mut finished = Vec::new();
+    for (entity, task) in rebuilds.building.iter_mut() {
+        if let Some(collider) = future::block_on(future::poll_once(task)) {
+            finished.push((*entity, collider));
+        }
+    }
+
+    for (entity, collider) in finished {
+        rebuilds.building.remove(&entity);
+
+        // The chunk may have been unloaded while its collider was built
+        let (_, rigid_body_handle, collider_handle) = match chunk_query.get(entity) {
+            Ok(chunk) => chunk,
+            Err(_) => continue,
+        };
+
+        if let Some(collider_handle) = collider_handle {
+            collider_set.remove(collider_handle.handle(), &mut bodies, false);
+            commands.remove_one::<ColliderHandleComponent>(entity);
+        }
+
+        if let Some(collider) = collider {
+            let new_handle = collider_set.insert(collider, rigid_body_handle.handle(), &mut bodies);
+            commands.insert_one(entity, ColliderHandleComponent::from(new_handle));
+        }
+    }
+
+    let delta = time.delta_seconds();
+    rebuilds.pending.retain(|entity, _| chunk_query.get(*entity).is_ok());
+    for pending in rebuilds.pending.values_mut() {
+        pending.quiet += delta;
+    }
+
+    let dynamic_bodies: Vec<Vec3> = body_query
+        .iter()
+        .filter(|(_, handle)| bodies.get(handle.handle()).map_or(false, |body| body.is_dynamic()))
+        .map(|(transform, _)| transform.translation)
+        .collect();
+    let half_extent = Vec3::new(
+        chunk_settings.width as f32,
+        chunk_settings.height as f32,
+        chunk_settings.length as f32,
+    ) * 0.5;
+
+    let mut ready: Vec<(Entity, f32)> = rebuilds
+        .pending
+        .iter()
+        .filter(|(entity, pending)| pending.quiet >= settings.debounce && !rebuilds.building.contains_key(entity))
+        .filter_map(|(entity, _)| {
+            let (transform, _, _) = chunk_query.get(*entity).ok()?;
+            let center = transform.translation + half_extent;
+            let distance = dynamic_bodies
+                .iter()
+                .map(|position| (*position - center).length_squared())
+                .fold(f32::INFINITY, f32::min);
+            Some((*entity, distance))
+        })
+        .collect();
+    ready.sort_by(|a, b| a.1.partial_cmp(&b.1).unwrap_or(Ordering::Equal));
+
+    for (entity, _) in ready.into_iter().take(settings.max_per_frame) {
+        let pending = rebuilds.pending.remove(&entity).unwrap();
+        let mode = physics_settings.mode;
+        let chunk_settings = *chunk_settings;
+        let task = task_pool.spawn(async move {
+            chunk_collider(mode, &chunk_settings, &pending.chunk).map(|collider| collider.build())
+        });
+        rebuilds.building.insert(entity, task);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chunk::test_util::settings;
+
+    fn ground(chunk_settings: &ChunkSettings, height: impl Fn(usize, usize) -> f32) -> Chunk {
+        let mut chunk = Chunk::new(chunk_settings);
+        for x in 0..chunk_settings.width {
+            for y in 0..chunk_settings.height {
+                for z in 0..chunk_settings.length {
+                    chunk.data[x][y][z] = (y as f32 - height(x, z)).max(-1.0).min(1.0);
+                }
+            }
+        }
+        chunk
+    }
+
+    #[test]
+    fn stride_samples_end_on_the_last_sample() {
+        assert_eq!(stride_samples(16, 2), vec![0, 2, 4, 6, 8, 10, 12, 14, 15]);
+        assert_eq!(stride_samples(16, 5), vec![0, 5, 10, 15]);
+    }
+
+    #[test]
+    fn rolling_ground_gets_a_heightfield() {
+        let chunk_settings = settings();
+        let chunk = ground(&chunk_settings, |x, z| 6.0 + (x as f32 * 0.4).sin() + (z as f32 * 0.3).cos());
+        assert!(heightfield_collider(&chunk_settings, &chunk).is_some());
+    }
+
+    #[test]
+    fn overhang_falls_back_to_a_trimesh() {
+        let chunk_settings = settings();
+        let mut chunk = ground(&chunk_settings, |_, _| 4.0);
+        chunk.data[5][10][5] = -1.0;
+
+        assert!(heightfield_collider(&chunk_settings, &chunk).is_none());
+        assert!(chunk_collider(PhysicsMeshMode::Auto { stride: 2 }, &chunk_settings, &chunk).is_some());
+    }
+
+    #[test]
+    fn empty_chunk_has_no_collider() {
+        let chunk_settings = settings();
+        let chunk = ground(&chunk_settings, |_, _| -10.0);
+        assert!(chunk_collider(PhysicsMeshMode::Full, &chunk_settings, &chunk).is_none());
+    }
+}
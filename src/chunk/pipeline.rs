@@ -11,8 +11,8 @@ use bevy::render::{
 };
 use bevy::{
     math::Vec3,
-    ecs::ResMut,
-    prelude::{Assets, Shader},
+    ecs::{Commands, ResMut},
+    prelude::{Assets, Handle, Shader},
     reflect::TypeUuid,
     render::{
         pipeline::{BlendDescriptor, BlendFactor, BlendOperation, ColorWrite},
@@ -20,10 +20,22 @@ use bevy::{
     },
 };
 
-use super::ChunkSettings;
-
 pub const MARCHING_MESH_MAT: &str = "marching_mesh_mat";
 pub const ATTRIBUTE_POINT_DATA: &str = "Vertex_Data";
+pub const ATTRIBUTE_MATERIAL: &str = "Vertex_Material";
+
+/// Colors of the painted materials, the same as `palette` in the fragment
+/// shader.
+pub const MATERIAL_PALETTE: [[f32; 3]; 8] = [
+    [1.0, 1.0, 1.0],
+    [0.36, 0.25, 0.16],
+    [0.29, 0.52, 0.21],
+    [0.52, 0.52, 0.5],
+    [0.86, 0.78, 0.55],
+    [0.95, 0.95, 0.97],
+    [0.2, 0.2, 0.22],
+    [0.62, 0.22, 0.16],
+];
 
 #[derive(RenderResources, Default, TypeUuid)]
 #[uuid = "3bf9e364-f29d-4d6c-92cf-93298466c500"]
@@ -82,22 +94,36 @@ pub fn default_marching_mesh_pipeline(mut shaders: ResMut<Assets<Shader>>) -> Pi
     }
 }
 
+pub struct ChunkRenderAssets {
+    pub pipeline: Handle<PipelineDescriptor>,
+    pub material: Handle<MarchMeshMaterial>,
+}
+
 pub fn setup_marching_mesh_pipeline(
+    commands: &mut Commands,
     mut pipelines: ResMut<Assets<PipelineDescriptor>>,
+    mut materials: ResMut<Assets<MarchMeshMaterial>>,
     mut render_graph: ResMut<RenderGraph>,
-    mut chunk_settings: ResMut<ChunkSettings>,
-    mut shaders: ResMut<Assets<Shader>>
+    shaders: ResMut<Assets<Shader>>
 ) {
-    // chunk_settings.pipeline_handle = pipelines.add(default_marching_mesh_pipeline(shaders));
+    let pipeline = pipelines.add(default_marching_mesh_pipeline(shaders));
 
-    // render_graph.add_system_node(
-    //     MARCHING_MESH_MAT,
-    //     AssetRenderResourcesNode::<MarchMeshMaterial>::new(true),
-    // );
+    render_graph.add_system_node(
+        MARCHING_MESH_MAT,
+        AssetRenderResourcesNode::<MarchMeshMaterial>::new(true),
+    );
 
-    // render_graph
-    //     .add_node_edge(MARCHING_MESH_MAT, base::node::MAIN_PASS)
-    //     .unwrap();
+    render_graph
+        .add_node_edge(MARCHING_MESH_MAT, base::node::MAIN_PASS)
+        .unwrap();
+
+    let material = materials.add(MarchMeshMaterial {
+        lightPos: Vec3::new(4.0, 8.0, 4.0),
+        lightColor: Vec3::new(1f32, 1f32, 1f32),
+        objectColor: Vec3::new(0.88, 0.32, 0.39),
+    });
+
+    commands.insert_resource(ChunkRenderAssets { pipeline, material });
 }
 
 const VERTEX_SHADER: &str = r#"
@@ -106,10 +132,12 @@ const VERTEX_SHADER: &str = r#"
 layout(location = 0) in vec3 Vertex_Position;
 layout(location = 1) in vec3 Vertex_Normal;
 layout(location = 2) in float Vertex_Data;
+layout(location = 3) in float Vertex_Material;
 
 layout(location = 0) out vec3 normal;
 layout(location = 1) out vec3 frag_pos;
 layout(location = 2) out float data;
+layout(location = 3) flat out float material;
 
 
 layout(set = 0, binding = 0) uniform Camera {
@@ -122,6 +150,7 @@ layout(set = 1, binding = 0) uniform Transform {
 
 void main() {
     data = Vertex_Data;
+    material = Vertex_Material;
     normal = mat3(transpose(inverse(Model))) * Vertex_Normal;  
     frag_pos = vec3(Model * vec4(Vertex_Position, 1.0));
     gl_Position = ViewProj * Model * vec4(Vertex_Position, 1.0);
@@ -136,6 +165,7 @@ layout(location = 0) out vec4 o_Target;
 layout(location = 0) in vec3 normal;
 layout(location = 1) in vec3 frag_pos;
 layout(location = 2) out float data;
+layout(location = 3) flat in float material;
 
 
 layout(set = 2, binding = 0) uniform MarchMeshMaterial_lightColor {
@@ -150,6 +180,18 @@ layout(set = 2, binding = 3) uniform MarchMeshMaterial_lightPos {
     vec3 lightPos;
 };
 
+// Painted materials, 0 keeps the position based coloring
+const vec3 palette[8] = vec3[8](
+    vec3(1.0, 1.0, 1.0),
+    vec3(0.36, 0.25, 0.16),
+    vec3(0.29, 0.52, 0.21),
+    vec3(0.52, 0.52, 0.5),
+    vec3(0.86, 0.78, 0.55),
+    vec3(0.95, 0.95, 0.97),
+    vec3(0.2, 0.2, 0.22),
+    vec3(0.62, 0.22, 0.16)
+);
+
 void main() {
     float ambientStrength = 0.1;
     vec3 ambient = ambientStrength * lightColor;
@@ -160,7 +202,9 @@ void main() {
     float diff = max(dot(norm, lightDir), 0.0);
     vec3 diffuse = diff * lightColor;
             
-    vec3 result = (ambient + diffuse) * normalize(frag_pos);
+    int index = clamp(int(material + 0.5), 0, 7);
+    vec3 color = index == 0 ? normalize(frag_pos) : palette[index];
+    vec3 result = (ambient + diffuse) * color;
     o_Target = vec4(result, 1.0);
 }
 "#;
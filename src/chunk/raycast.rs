@@ -0,0 +1,247 @@
+use std::collections::HashSet;
+use std::ops::Deref;
+
+use bevy::prelude::*;
+
+use super::map::{ChunkCoord, ChunkMap};
+use super::terrain::TerrainView;
+use super::{Chunk, ChunkSettings};
+use crate::camera::cursor_ray;
+
+const REFINE_STEPS: usize = 12;
+
+pub struct TerrainHit {
+    pub position: Vec3,
+    /// Points out of the ground, along the density gradient.
+    pub normal: Vec3,
+    pub distance: f32,
+    pub chunk: ChunkCoord,
+    pub entity: Option<Entity>,
+}
+
+impl<'a, C: Deref<Target = Chunk>> TerrainView<'a, C> {
+    /// Marches a ray cell by cell through the density field and returns the
+    /// first place it goes from air into the ground. Unloaded chunks are
+    /// skipped over, so the ray can't hit what isn't loaded.
+    pub fn raycast(&self, origin: Vec3, direction: Vec3, max_distance: f32) -> Option<TerrainHit> {
+        let direction = direction.normalize();
+        let threshold = self.chunk_settings().threshold;
+        let origin_array = [origin.x, origin.y, origin.z];
+        let direction_array = [direction.x, direction.y, direction.z];
+
+        let mut t_max = [f32::INFINITY; 3];
+        let mut t_delta = [f32::INFINITY; 3];
+        for axis in 0..3 {
+            let cell = origin_array[axis].floor();
+            if direction_array[axis] > 0.0 {
+                t_delta[axis] = 1.0 / direction_array[axis];
+                t_max[axis] = (cell + 1.0 - origin_array[axis]) / direction_array[axis];
+            } else if direction_array[axis] < 0.0 {
+                t_delta[axis] = -1.0 / direction_array[axis];
+                t_max[axis] = (origin_array[axis] - cell) / -direction_array[axis];
+            }
+        }
+
+        let mut t = 0f32;
+        let mut previous = self.sample(origin);
+
+        while t < max_distance {
+            let axis = if t_max[0] < t_max[1] {
+                if t_max[0] < t_max[2] {
+                    0
+                } else {
+                    2
+                }
+            } else if t_max[1] < t_max[2] {
+                1
+            } else {
+                2
+            };
+
+            let t_exit = t_max[axis].min(max_distance);
+            let exit_value = self.sample(origin + direction * t_exit);
+
+            if let (Some(entry), Some(exit)) = (previous, exit_value) {
+                if entry > threshold && exit <= threshold {
+                    let distance = self.refine(origin, direction, t, t_exit);
+                    let position = origin + direction * distance;
+                    let chunk = ChunkCoord::from_world(self.chunk_settings(), position);
+
+                    return Some(TerrainHit {
+                        position,
+                        normal: self.normal(position),
+                        distance,
+                        chunk,
+                        entity: self.entity(chunk),
+                    });
+                }
+            }
+
+            previous = exit_value;
+            t = t_exit;
+            t_max[axis] += t_delta[axis];
+        }
+
+        None
+    }
+
+    /// Bisects between a point in the air and one in the ground until it
+    /// lands on the isosurface.
+    fn refine(&self, origin: Vec3, direction: Vec3, mut air: f32, mut ground: f32) -> f32 {
+        let threshold = self.chunk_settings().threshold;
+
+        for _ in 0..REFINE_STEPS {
+            let middle = (air + ground) * 0.5;
+            match self.sample(origin + direction * middle) {
+                Some(value) if value > threshold => air = middle,
+                _ => ground = middle,
+            }
+        }
+
+        (air + ground) * 0.5
+    }
+
+    /// Surface normal from the central difference gradient of the field.
+    pub fn normal(&self, position: Vec3) -> Vec3 {
+        let h = 0.5;
+        let difference = |offset: Vec3| {
+            let a = self.sample(position + offset).unwrap_or(0.0);
+            let b = self.sample(position - offset).unwrap_or(0.0);
+            a - b
+        };
+
+        let gradient = Vec3::new(
+            difference(Vec3::new(h, 0.0, 0.0)),
+            difference(Vec3::new(0.0, h, 0.0)),
+            difference(Vec3::new(0.0, 0.0, h)),
+        );
+
+        if gradient.length_squared() > 0.0 {
+            gradient.normalize()
+        } else {
+            Vec3::unit_y()
+        }
+    }
+}
+
+/// Marks the camera whose cursor ray feeds `TerrainCursor`.
+#[derive(Default)]
+pub struct TerrainCursorSource;
+
+/// The terrain under the mouse cursor this frame.
+#[derive(Default)]
+pub struct TerrainCursor {
+    pub ray: Option<(Vec3, Vec3)>,
+    pub hit: Option<TerrainHit>,
+}
+
+pub struct RaycastSettings {
+    pub max_distance: f32,
+}
+
+impl Default for RaycastSettings {
+    fn default() -> Self {
+        Self { max_distance: 500.0 }
+    }
+}
+
+/// Loaded chunks within a unit of the ray. The ray is walked in chunk sized
+/// segments so a long ray only looks up the chunks next to it, not every
+/// chunk in its bounding box.
+fn chunks_along_ray(
+    chunk_map: &ChunkMap,
+    chunk_settings: &ChunkSettings,
+    origin: Vec3,
+    direction: Vec3,
+    max_distance: f32,
+) -> HashSet<Entity> {
+    let direction = direction.normalize();
+    let segment = (chunk_settings.width.min(chunk_settings.height).min(chunk_settings.length) - 1) as f32;
+    let padding = Vec3::one();
+
+    let mut entities = HashSet::new();
+    let mut start = 0f32;
+    while start < max_distance {
+        let end = (start + segment).min(max_distance);
+        let a = origin + direction * start;
+        let b = origin + direction * end;
+        for (_, entity) in chunk_map.chunks_in_aabb(chunk_settings, a.min(b) - padding, a.max(b) + padding) {
+            entities.insert(entity);
+        }
+        start = end;
+    }
+
+    entities
+}
+
+pub fn update_terrain_cursor(
+    windows: Res<Windows>,
+    chunk_settings: Res<ChunkSettings>,
+    raycast_settings: Res<RaycastSettings>,
+    chunk_map: Res<ChunkMap>,
+    mut cursor: ResMut<TerrainCursor>,
+    camera_query: Query<(&Camera, &GlobalTransform), With<TerrainCursorSource>>,
+    chunk_query: Query<(Entity, &ChunkCoord, &Chunk)>,
+) {
+    cursor.ray = None;
+    cursor.hit = None;
+
+    for (camera, transform) in camera_query.iter() {
+        if let Some((origin, direction)) = cursor_ray(&windows, camera, transform) {
+            let chunks = chunks_along_ray(
+                &chunk_map,
+                &chunk_settings,
+                origin,
+                direction,
+                raycast_settings.max_distance,
+            );
+            let terrain = TerrainView::new(
+                &chunk_settings,
+                chunks.into_iter().filter_map(|entity| chunk_query.get(entity).ok()),
+            );
+            cursor.ray = Some((origin, direction));
+            cursor.hit = terrain.raycast(origin, direction, raycast_settings.max_distance);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chunk::test_util::settings;
+
+    fn flat_ground(chunk_settings: &ChunkSettings, height: f32) -> Chunk {
+        let mut chunk = Chunk::new(chunk_settings);
+        for x in 0..chunk_settings.width {
+            for y in 0..chunk_settings.height {
+                for z in 0..chunk_settings.length {
+                    chunk.data[x][y][z] = (y as f32 - height).max(-1.0).min(1.0);
+                }
+            }
+        }
+        chunk
+    }
+
+    #[test]
+    fn ray_hits_the_ground_surface() {
+        let chunk_settings = settings();
+        let chunk = flat_ground(&chunk_settings, 5.5);
+        let terrain = TerrainView::detached(&chunk_settings, ChunkCoord::new(0, 0, 0), &chunk);
+
+        let hit = terrain
+            .raycast(Vec3::new(4.2, 12.0, 7.7), -Vec3::unit_y(), 100.0)
+            .unwrap();
+        assert!((hit.position.y - 5.5).abs() < 0.01);
+        assert!(hit.normal.y > 0.99);
+        assert_eq!(hit.chunk, ChunkCoord::new(0, 0, 0));
+    }
+
+    #[test]
+    fn ray_pointing_away_misses() {
+        let chunk_settings = settings();
+        let chunk = flat_ground(&chunk_settings, 5.5);
+        let terrain = TerrainView::detached(&chunk_settings, ChunkCoord::new(0, 0, 0), &chunk);
+
+        assert!(terrain.raycast(Vec3::new(4.2, 12.0, 7.7), Vec3::unit_y(), 100.0).is_none());
+    }
+}
@@ -0,0 +1,163 @@
+use std::collections::HashSet;
+
+use bevy::prelude::*;
+
+use super::pipeline::ChunkRenderAssets;
+use super::map::{ChunkCoord, ChunkMap};
+use super::physics::PhysicsMeshSettings;
+use super::{spawn_chunk, Chunk, ChunkSettings};
+use crate::generation::{generate_chunk, TerrainSource};
+use crate::persistence::{EditJournal, PendingSaves, RegionStore};
+
+/// Keeps every chunk within `radius` chunks horizontally and
+/// `vertical_radius` chunks vertically of this entity loaded.
+pub struct ChunkLoader {
+    pub radius: i32,
+    pub vertical_radius: i32,
+}
+
+impl Default for ChunkLoader {
+    fn default() -> Self {
+        Self {
+            radius: 5,
+            vertical_radius: 3,
+        }
+    }
+}
+
+impl ChunkLoader {
+    fn contains(&self, center: ChunkCoord, coord: ChunkCoord, margin: i32) -> bool {
+        let radius = self.radius + margin;
+        let dx = coord.x - center.x;
+        let dz = coord.z - center.z;
+
+        dx * dx + dz * dz <= radius * radius && (coord.y - center.y).abs() <= self.vertical_radius + margin
+    }
+}
+
+pub struct StreamingSettings {
+    /// Upper bound on chunks generated per frame, so fast movement spreads
+    /// the work over several frames instead of stalling one.
+    pub max_spawns_per_frame: usize,
+    /// Extra chunks a loaded chunk may fall behind before it is unloaded, so
+    /// moving back and forth over a border doesn't thrash.
+    pub unload_margin: i32,
+}
+
+impl Default for StreamingSettings {
+    fn default() -> Self {
+        Self {
+            max_spawns_per_frame: 2,
+            unload_margin: 2,
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments)] // Bevy systems take their resources as parameters
+pub fn stream_chunks(
+    commands: &mut Commands,
+    chunk_settings: Res<ChunkSettings>,
+    streaming_settings: Res<StreamingSettings>,
+    physics_settings: Res<PhysicsMeshSettings>,
+    terrain_source: Res<TerrainSource>,
+    render_assets: Res<ChunkRenderAssets>,
+    region_store: Res<RegionStore>,
+    journal: Res<EditJournal>,
+    mut pending_saves: ResMut<PendingSaves>,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut chunk_map: ResMut<ChunkMap>,
+    loader_query: Query<(&ChunkLoader, &GlobalTransform)>,
+    chunk_query: Query<&Chunk>,
+) {
+    let loaders: Vec<(ChunkCoord, &ChunkLoader)> = loader_query
+        .iter()
+        .map(|(loader, transform)| (ChunkCoord::from_world(&chunk_settings, transform.translation), loader))
+        .collect();
+
+    if loaders.is_empty() {
+        return;
+    }
+
+    let unload_margin = streaming_settings.unload_margin;
+    let far_chunks: Vec<ChunkCoord> = chunk_map
+        .iter()
+        .map(|(coord, _)| coord)
+        .filter(|coord| {
+            loaders
+                .iter()
+                .all(|(center, loader)| !loader.contains(*center, *coord, unload_margin))
+        })
+        .collect();
+
+    for coord in far_chunks {
+        if let Some(entity) = chunk_map.remove(coord) {
+            if pending_saves.chunks.remove(&coord) {
+                if let Ok(chunk) = chunk_query.get(entity) {
+                    if let Err(err) = region_store.save_chunks(&chunk_settings, std::iter::once((coord, chunk))) {
+                        error!("failed to save chunk {:?}: {}", coord, err);
+                    }
+                }
+            }
+
+            commands.despawn_recursive(entity);
+        }
+    }
+
+    let mut missing: HashSet<ChunkCoord> = HashSet::new();
+    for (center, loader) in loaders.iter() {
+        for y in -loader.vertical_radius..=loader.vertical_radius {
+            for z in -loader.radius..=loader.radius {
+                for x in -loader.radius..=loader.radius {
+                    let coord = ChunkCoord::new(center.x + x, center.y + y, center.z + z);
+                    if loader.contains(*center, coord, 0) && !chunk_map.contains(coord) {
+                        missing.insert(coord);
+                    }
+                }
+            }
+        }
+    }
+
+    let mut missing: Vec<ChunkCoord> = missing.into_iter().collect();
+    missing.sort_by_key(|coord| {
+        loaders
+            .iter()
+            .map(|(center, _)| coord.distance_squared(*center))
+            .min()
+            .unwrap_or(0)
+    });
+
+    for coord in missing.into_iter().take(streaming_settings.max_spawns_per_frame) {
+        let saved = match region_store.load_chunk(&chunk_settings, coord) {
+            Ok(saved) => saved,
+            Err(err) => {
+                warn!("failed to load chunk {:?}, regenerating it: {}", coord, err);
+                None
+            }
+        };
+
+        // Chunks never saved are rebuilt from the generator plus the journal
+        let chunk = match saved {
+            Some(decoded) => {
+                if decoded.migrated {
+                    pending_saves.chunks.insert(coord);
+                }
+                decoded.chunk
+            }
+            None => {
+                let mut chunk = generate_chunk(&chunk_settings, &terrain_source, coord.origin(&chunk_settings));
+                journal.replay(&chunk_settings, coord, &mut chunk);
+                chunk
+            }
+        };
+        let entity = spawn_chunk(
+            commands,
+            &mut meshes,
+            &render_assets,
+            &chunk_settings,
+            &physics_settings,
+            coord,
+            chunk,
+        );
+        chunk_map.insert(coord, entity);
+    }
+}
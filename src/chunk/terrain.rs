@@ -0,0 +1,286 @@
+use std::collections::HashMap;
+use std::ops::{Deref, DerefMut};
+
+use bevy::prelude::*;
+
+use super::map::ChunkCoord;
+use super::{Chunk, ChunkSettings};
+
+/// World space access to the density field of the loaded chunks.
+///
+/// Built from a chunk query each time it's needed, either read only from
+/// `Query<(Entity, &ChunkCoord, &Chunk)>` or writable from
+/// `Query<(Entity, &ChunkCoord, &mut Chunk)>`. Voxels are integer world
+/// positions; a voxel on a chunk border lives in every chunk sharing that
+/// border and writes go to all of them.
+pub struct TerrainView<'a, C> {
+    chunk_settings: &'a ChunkSettings,
+    chunks: HashMap<ChunkCoord, (Option<Entity>, C)>,
+}
+
+impl<'a, C: Deref<Target = Chunk>> TerrainView<'a, C> {
+    pub fn new<'q>(
+        chunk_settings: &'a ChunkSettings,
+        chunks: impl Iterator<Item = (Entity, &'q ChunkCoord, C)>,
+    ) -> Self {
+        TerrainView {
+            chunk_settings,
+            chunks: chunks.map(|(entity, coord, chunk)| (*coord, (Some(entity), chunk))).collect(),
+        }
+    }
+
+    /// A view of a single chunk that isn't spawned yet, e.g. one being
+    /// generated.
+    pub fn detached(chunk_settings: &'a ChunkSettings, coord: ChunkCoord, chunk: C) -> Self {
+        let mut chunks = HashMap::new();
+        chunks.insert(coord, (None, chunk));
+        TerrainView { chunk_settings, chunks }
+    }
+
+    pub fn chunk_settings(&self) -> &ChunkSettings {
+        self.chunk_settings
+    }
+
+    pub fn chunk(&self, coord: ChunkCoord) -> Option<&Chunk> {
+        self.chunks.get(&coord).map(|(_, chunk)| &**chunk)
+    }
+
+    pub fn entity(&self, coord: ChunkCoord) -> Option<Entity> {
+        self.chunks.get(&coord).and_then(|(entity, _)| *entity)
+    }
+
+    /// Every chunk position holding the voxel, loaded or not: one inside a
+    /// chunk, up to eight on a shared corner.
+    pub fn voxel_locations(&self, voxel: [i32; 3]) -> impl Iterator<Item = (ChunkCoord, [usize; 3])> {
+        voxel_locations(self.chunk_settings, voxel)
+    }
+
+    /// Density of the voxel, `None` if no chunk holding it is loaded.
+    pub fn voxel(&self, voxel: [i32; 3]) -> Option<f32> {
+        for (coord, [x, y, z]) in self.voxel_locations(voxel) {
+            if let Some((_, chunk)) = self.chunks.get(&coord) {
+                return Some(chunk.data[x][y][z]);
+            }
+        }
+
+        None
+    }
+
+    pub fn material(&self, voxel: [i32; 3]) -> Option<u8> {
+        for (coord, [x, y, z]) in self.voxel_locations(voxel) {
+            if let Some((_, chunk)) = self.chunks.get(&coord) {
+                return Some(chunk.materials[x][y][z]);
+            }
+        }
+
+        None
+    }
+
+    /// Trilinearly interpolated density at a world position.
+    pub fn sample(&self, position: Vec3) -> Option<f32> {
+        let base = [
+            position.x.floor() as i32,
+            position.y.floor() as i32,
+            position.z.floor() as i32,
+        ];
+        let t = [
+            position.x - base[0] as f32,
+            position.y - base[1] as f32,
+            position.z - base[2] as f32,
+        ];
+
+        let mut corners = [0f32; 8];
+        for (i, corner) in corners.iter_mut().enumerate() {
+            let offset = [(i & 1) as i32, ((i >> 1) & 1) as i32, ((i >> 2) & 1) as i32];
+            *corner = self.voxel([base[0] + offset[0], base[1] + offset[1], base[2] + offset[2]])?;
+        }
+
+        let x00 = corners[0] + (corners[1] - corners[0]) * t[0];
+        let x10 = corners[2] + (corners[3] - corners[2]) * t[0];
+        let x01 = corners[4] + (corners[5] - corners[4]) * t[0];
+        let x11 = corners[6] + (corners[7] - corners[6]) * t[0];
+        let y0 = x00 + (x10 - x00) * t[1];
+        let y1 = x01 + (x11 - x01) * t[1];
+
+        Some(y0 + (y1 - y0) * t[2])
+    }
+
+    /// Calls `f` with the world voxel and density of every loaded voxel in
+    /// the box.
+    pub fn for_each_in_region(&self, min: Vec3, max: Vec3, mut f: impl FnMut([i32; 3], f32)) {
+        for voxel in voxels_in_region(min, max) {
+            if let Some(value) = self.voxel(voxel) {
+                f(voxel, value);
+            }
+        }
+    }
+}
+
+impl<'a, C: DerefMut<Target = Chunk>> TerrainView<'a, C> {
+    /// Writes the voxel into every loaded chunk holding it. Returns false if
+    /// none of them is loaded.
+    pub fn set_voxel(&mut self, voxel: [i32; 3], value: f32) -> bool {
+        let mut written = false;
+        for (coord, [x, y, z]) in self.voxel_locations(voxel) {
+            if let Some((_, chunk)) = self.chunks.get_mut(&coord) {
+                chunk.data[x][y][z] = value;
+                written = true;
+            }
+        }
+
+        written
+    }
+
+    pub fn set_material(&mut self, voxel: [i32; 3], material: u8) -> bool {
+        let mut written = false;
+        for (coord, [x, y, z]) in self.voxel_locations(voxel) {
+            if let Some((_, chunk)) = self.chunks.get_mut(&coord) {
+                chunk.materials[x][y][z] = material;
+                written = true;
+            }
+        }
+
+        written
+    }
+
+    pub fn add_voxel(&mut self, voxel: [i32; 3], amount: f32) -> bool {
+        match self.voxel(voxel) {
+            Some(value) => self.set_voxel(voxel, value + amount),
+            None => false,
+        }
+    }
+
+    /// Sets the voxel nearest to a world position.
+    pub fn set(&mut self, position: Vec3, value: f32) -> bool {
+        self.set_voxel(nearest_voxel(position), value)
+    }
+
+    /// Adds to the voxel nearest to a world position.
+    pub fn add(&mut self, position: Vec3, amount: f32) -> bool {
+        self.add_voxel(nearest_voxel(position), amount)
+    }
+
+    /// Replaces every loaded voxel in the box with `f(voxel, density)`.
+    pub fn update_region(&mut self, min: Vec3, max: Vec3, mut f: impl FnMut([i32; 3], f32) -> f32) {
+        for voxel in voxels_in_region(min, max) {
+            if let Some(value) = self.voxel(voxel) {
+                let new_value = f(voxel, value);
+                if new_value != value {
+                    self.set_voxel(voxel, new_value);
+                }
+            }
+        }
+    }
+}
+
+/// Every chunk position holding the voxel: one inside a chunk, up to eight
+/// on a shared corner. The chunk containing the voxel comes first. Lookups
+/// run for every sample a raycast or edit touches, so nothing is allocated.
+pub fn voxel_locations(chunk_settings: &ChunkSettings, voxel: [i32; 3]) -> impl Iterator<Item = (ChunkCoord, [usize; 3])> {
+    let steps = [
+        (chunk_settings.width - 1) as i32,
+        (chunk_settings.height - 1) as i32,
+        (chunk_settings.length - 1) as i32,
+    ];
+    let chunk = [
+        voxel[0].div_euclid(steps[0]),
+        voxel[1].div_euclid(steps[1]),
+        voxel[2].div_euclid(steps[2]),
+    ];
+    let local = [
+        voxel[0].rem_euclid(steps[0]) as usize,
+        voxel[1].rem_euclid(steps[1]) as usize,
+        voxel[2].rem_euclid(steps[2]) as usize,
+    ];
+
+    // Bit `axis` of `corner` picks the chunk below on that axis, which only
+    // holds the voxel when it sits on the shared border
+    (0..8usize)
+        .filter(move |corner| (0..3).all(|axis| (corner >> axis) & 1 == 0 || local[axis] == 0))
+        .map(move |corner| {
+            let mut coord = chunk;
+            let mut index = local;
+            for axis in 0..3 {
+                if (corner >> axis) & 1 == 1 {
+                    coord[axis] -= 1;
+                    index[axis] = steps[axis] as usize;
+                }
+            }
+            (ChunkCoord::new(coord[0], coord[1], coord[2]), index)
+        })
+}
+
+pub fn nearest_voxel(position: Vec3) -> [i32; 3] {
+    [
+        position.x.round() as i32,
+        position.y.round() as i32,
+        position.z.round() as i32,
+    ]
+}
+
+pub fn voxel_position(voxel: [i32; 3]) -> Vec3 {
+    Vec3::new(voxel[0] as f32, voxel[1] as f32, voxel[2] as f32)
+}
+
+/// Integer voxels inside the box, bounds included.
+pub fn voxels_in_region(min: Vec3, max: Vec3) -> impl Iterator<Item = [i32; 3]> {
+    let min = [min.x.ceil() as i32, min.y.ceil() as i32, min.z.ceil() as i32];
+    let max = [max.x.floor() as i32, max.y.floor() as i32, max.z.floor() as i32];
+
+    (min[1]..=max[1]).flat_map(move |y| {
+        (min[2]..=max[2]).flat_map(move |z| (min[0]..=max[0]).map(move |x| [x, y, z]))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chunk::test_util::settings;
+
+    #[test]
+    fn inner_voxel_lives_in_one_chunk() {
+        let locations: Vec<_> = voxel_locations(&settings(), [20, 3, -4]).collect();
+        assert_eq!(locations, vec![(ChunkCoord::new(1, 0, -1), [5, 3, 11])]);
+    }
+
+    #[test]
+    fn border_voxel_lives_in_both_chunks() {
+        let locations: Vec<_> = voxel_locations(&settings(), [15, 3, 4]).collect();
+        assert_eq!(
+            locations,
+            vec![(ChunkCoord::new(1, 0, 0), [0, 3, 4]), (ChunkCoord::new(0, 0, 0), [15, 3, 4])]
+        );
+    }
+
+    #[test]
+    fn corner_voxel_lives_in_eight_chunks() {
+        let locations: Vec<_> = voxel_locations(&settings(), [0, 0, 0]).collect();
+        assert_eq!(locations.len(), 8);
+        assert_eq!(locations[0], (ChunkCoord::new(0, 0, 0), [0, 0, 0]));
+        assert!(locations.contains(&(ChunkCoord::new(-1, -1, -1), [15, 15, 15])));
+    }
+
+    #[test]
+    fn writes_reach_every_chunk_sharing_the_voxel() {
+        let chunk_settings = settings();
+        let mut left = Chunk::new(&chunk_settings);
+        let mut right = Chunk::new(&chunk_settings);
+        let mut terrain = TerrainView {
+            chunk_settings: &chunk_settings,
+            chunks: vec![
+                (ChunkCoord::new(0, 0, 0), (None, &mut left)),
+                (ChunkCoord::new(1, 0, 0), (None, &mut right)),
+            ]
+            .into_iter()
+            .collect(),
+        };
+
+        assert!(terrain.set_voxel([15, 2, 2], -1.0));
+        assert!(!terrain.set_voxel([40, 2, 2], -1.0));
+        assert_eq!(terrain.voxel([15, 2, 2]), Some(-1.0));
+        drop(terrain);
+
+        assert_eq!(left.data[15][2][2], -1.0);
+        assert_eq!(right.data[0][2][2], -1.0);
+    }
+}
@@ -0,0 +1,26 @@
+//! Fixtures shared by the unit tests.
+
+use super::{Chunk, ChunkSettings};
+
+/// 16³ chunks with the surface at density 0.
+pub fn settings() -> ChunkSettings {
+    ChunkSettings {
+        width: 16,
+        height: 16,
+        length: 16,
+        threshold: 0.0,
+    }
+}
+
+/// A chunk holding nothing but air.
+pub fn air(chunk_settings: &ChunkSettings) -> Chunk {
+    let mut chunk = Chunk::new(chunk_settings);
+    for plane in chunk.data.iter_mut() {
+        for row in plane.iter_mut() {
+            for density in row.iter_mut() {
+                *density = 1.0;
+            }
+        }
+    }
+    chunk
+}
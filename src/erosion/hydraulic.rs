@@ -0,0 +1,221 @@
+use super::{Rng, SurfaceHeightmap};
+
+/// Droplet based hydraulic erosion. Each droplet spawns at a random point,
+/// runs downhill picking up sediment on steep slopes and dropping it where it
+/// slows down, which carves riverbeds and fills valleys.
+#[derive(Clone)]
+pub struct HydraulicErosion {
+    pub seed: u64,
+    pub iterations: usize,
+    pub erosion_radius: i32,
+    pub inertia: f32,
+    pub sediment_capacity_factor: f32,
+    pub min_sediment_capacity: f32,
+    pub erode_speed: f32,
+    pub deposit_speed: f32,
+    pub evaporate_speed: f32,
+    pub gravity: f32,
+    pub max_droplet_lifetime: usize,
+    pub initial_water_volume: f32,
+    pub initial_speed: f32,
+}
+
+impl Default for HydraulicErosion {
+    fn default() -> Self {
+        Self {
+            seed: 1337,
+            iterations: 50_000,
+            erosion_radius: 3,
+            inertia: 0.05,
+            sediment_capacity_factor: 4.0,
+            min_sediment_capacity: 0.01,
+            erode_speed: 0.3,
+            deposit_speed: 0.3,
+            evaporate_speed: 0.01,
+            gravity: 4.0,
+            max_droplet_lifetime: 30,
+            initial_water_volume: 1.0,
+            initial_speed: 1.0,
+        }
+    }
+}
+
+impl HydraulicErosion {
+    pub fn erode(&self, heightmap: &mut SurfaceHeightmap) {
+        if heightmap.width < 2 || heightmap.length < 2 {
+            return;
+        }
+
+        let brush = self.erosion_brush();
+        let mut rng = Rng::new(self.seed);
+
+        for _ in 0..self.iterations {
+            let mut pos_x = rng.next_f32() * (heightmap.width - 1) as f32;
+            let mut pos_z = rng.next_f32() * (heightmap.length - 1) as f32;
+            let mut dir_x = 0f32;
+            let mut dir_z = 0f32;
+            let mut speed = self.initial_speed;
+            let mut water = self.initial_water_volume;
+            let mut sediment = 0f32;
+
+            for _ in 0..self.max_droplet_lifetime {
+                let node_x = pos_x as usize;
+                let node_z = pos_z as usize;
+                let cell_offset_x = pos_x - node_x as f32;
+                let cell_offset_z = pos_z - node_z as f32;
+
+                let (height, gradient_x, gradient_z) = match heightmap.height_and_gradient(pos_x, pos_z) {
+                    Some(sample) => sample,
+                    None => break,
+                };
+
+                dir_x = dir_x * self.inertia - gradient_x * (1.0 - self.inertia);
+                dir_z = dir_z * self.inertia - gradient_z * (1.0 - self.inertia);
+
+                let len = (dir_x * dir_x + dir_z * dir_z).sqrt();
+                if len == 0.0 {
+                    break;
+                }
+                dir_x /= len;
+                dir_z /= len;
+
+                pos_x += dir_x;
+                pos_z += dir_z;
+
+                if pos_x < 0.0 || pos_z < 0.0 {
+                    break;
+                }
+
+                let new_height = match heightmap.height_and_gradient(pos_x, pos_z) {
+                    Some((new_height, _, _)) => new_height,
+                    None => break,
+                };
+                let delta_height = new_height - height;
+
+                let sediment_capacity = (-delta_height * speed * water * self.sediment_capacity_factor)
+                    .max(self.min_sediment_capacity);
+
+                if sediment > sediment_capacity || delta_height > 0.0 {
+                    let amount_to_deposit = if delta_height > 0.0 {
+                        delta_height.min(sediment)
+                    } else {
+                        (sediment - sediment_capacity) * self.deposit_speed
+                    };
+                    sediment -= amount_to_deposit;
+
+                    heightmap.add(node_x, node_z, amount_to_deposit * (1.0 - cell_offset_x) * (1.0 - cell_offset_z));
+                    heightmap.add(node_x + 1, node_z, amount_to_deposit * cell_offset_x * (1.0 - cell_offset_z));
+                    heightmap.add(node_x, node_z + 1, amount_to_deposit * (1.0 - cell_offset_x) * cell_offset_z);
+                    heightmap.add(node_x + 1, node_z + 1, amount_to_deposit * cell_offset_x * cell_offset_z);
+                } else {
+                    let amount_to_erode = ((sediment_capacity - sediment) * self.erode_speed).min(-delta_height);
+
+                    for (offset_x, offset_z, weight) in brush.iter() {
+                        let x = node_x as i32 + offset_x;
+                        let z = node_z as i32 + offset_z;
+                        if x < 0 || z < 0 || x as usize >= heightmap.width || z as usize >= heightmap.length {
+                            continue;
+                        }
+
+                        let (x, z) = (x as usize, z as usize);
+                        if !heightmap.valid[heightmap.index(x, z)] {
+                            continue;
+                        }
+
+                        let delta_sediment = amount_to_erode * weight;
+                        heightmap.add(x, z, -delta_sediment);
+                        sediment += delta_sediment;
+                    }
+                }
+
+                speed = self.droplet_speed(speed, delta_height);
+                water *= 1.0 - self.evaporate_speed;
+            }
+        }
+    }
+
+    /// Speed after moving by `delta_height`. Going downhill, where the delta
+    /// is negative, speeds the droplet up.
+    fn droplet_speed(&self, speed: f32, delta_height: f32) -> f32 {
+        (speed * speed - delta_height * self.gravity).max(0.0).sqrt()
+    }
+
+    fn erosion_brush(&self) -> Vec<(i32, i32, f32)> {
+        let radius = self.erosion_radius.max(1);
+        let mut brush = Vec::new();
+        let mut weight_sum = 0f32;
+
+        for offset_z in -radius..=radius {
+            for offset_x in -radius..=radius {
+                let distance = ((offset_x * offset_x + offset_z * offset_z) as f32).sqrt();
+                if distance < radius as f32 {
+                    let weight = 1.0 - distance / radius as f32;
+                    weight_sum += weight;
+                    brush.push((offset_x, offset_z, weight));
+                }
+            }
+        }
+
+        for (_, _, weight) in brush.iter_mut() {
+            *weight /= weight_sum;
+        }
+
+        brush
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::math::Vec3;
+
+    use super::*;
+    use crate::chunk::{test_util::settings, Chunk};
+
+    fn sloped_heightmap() -> SurfaceHeightmap {
+        let chunk_settings = settings();
+        let mut chunk = Chunk::new(&chunk_settings);
+        for x in 0..16 {
+            for y in 0..16 {
+                for z in 0..16 {
+                    let surface = 3.0 + x as f32 * 0.5 + (z as f32 * 0.7).sin();
+                    chunk.data[x][y][z] = (y as f32 - surface).max(-1.0).min(1.0);
+                }
+            }
+        }
+
+        SurfaceHeightmap::from_chunks(&chunk_settings, std::iter::once((&chunk, Vec3::zero()))).unwrap()
+    }
+
+    fn eroded(seed: u64) -> Vec<f32> {
+        let mut heightmap = sloped_heightmap();
+        HydraulicErosion {
+            seed,
+            iterations: 2_000,
+            ..Default::default()
+        }
+        .erode(&mut heightmap);
+        heightmap.heights
+    }
+
+    #[test]
+    fn same_seed_gives_same_heightmap() {
+        assert_eq!(eroded(7), eroded(7));
+    }
+
+    #[test]
+    fn different_seeds_give_different_heightmaps() {
+        assert_ne!(eroded(7), eroded(8));
+    }
+
+    #[test]
+    fn erosion_changes_the_surface() {
+        assert_ne!(eroded(7), sloped_heightmap().heights);
+    }
+
+    #[test]
+    fn droplets_speed_up_downhill() {
+        let erosion = HydraulicErosion::default();
+        assert!(erosion.droplet_speed(1.0, -0.5) > 1.0);
+        assert!(erosion.droplet_speed(1.0, 0.1) < 1.0);
+    }
+}
@@ -0,0 +1,445 @@
+use std::collections::HashSet;
+use std::ops::DerefMut;
+
+use bevy::prelude::*;
+use bevy::tasks::{AsyncComputeTaskPool, Task};
+use futures_lite::future;
+
+use crate::chunk::{map::ChunkCoord, Chunk, ChunkSettings, ChunksRewritten};
+use crate::sculpt::TerrainAuthority;
+
+pub mod hydraulic;
+pub mod thermal;
+
+pub use hydraulic::HydraulicErosion;
+pub use thermal::ThermalErosion;
+
+/// Small xorshift generator so erosion runs are reproducible for a given seed.
+pub struct Rng {
+    state: u64,
+}
+
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        Rng {
+            state: (seed ^ 0x9E37_79B9_7F4A_7C15) | 1,
+        }
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        let mut x = self.state;
+        x ^= x >> 12;
+        x ^= x << 25;
+        x ^= x >> 27;
+        self.state = x;
+        x.wrapping_mul(0x2545_F491_4F6C_DD1D)
+    }
+
+    /// Uniform value in `[0, 1)`.
+    pub fn next_f32(&mut self) -> f32 {
+        (self.next_u64() >> 40) as f32 / (1u64 << 24) as f32
+    }
+}
+
+/// Top surface of the loaded chunks, flattened into one world space grid.
+/// Stacked chunks are merged, each column keeps its highest crossing.
+pub struct SurfaceHeightmap {
+    pub origin_x: i32,
+    pub origin_z: i32,
+    pub width: usize,
+    pub length: usize,
+    pub heights: Vec<f32>,
+    pub valid: Vec<bool>,
+    original: Vec<f32>,
+}
+
+impl SurfaceHeightmap {
+    pub fn from_chunks<'a>(
+        chunk_settings: &ChunkSettings,
+        chunks: impl Iterator<Item = (&'a Chunk, Vec3)>,
+    ) -> Option<Self> {
+        let chunks: Vec<(&Chunk, Vec3)> = chunks.collect();
+        if chunks.is_empty() {
+            return None;
+        }
+
+        let mut min_x = i32::MAX;
+        let mut min_z = i32::MAX;
+        let mut max_x = i32::MIN;
+        let mut max_z = i32::MIN;
+
+        for (_, position) in chunks.iter() {
+            min_x = min_x.min(position.x.round() as i32);
+            min_z = min_z.min(position.z.round() as i32);
+            max_x = max_x.max(position.x.round() as i32 + chunk_settings.width as i32);
+            max_z = max_z.max(position.z.round() as i32 + chunk_settings.length as i32);
+        }
+
+        let width = (max_x - min_x) as usize;
+        let length = (max_z - min_z) as usize;
+        let mut heightmap = SurfaceHeightmap {
+            origin_x: min_x,
+            origin_z: min_z,
+            width,
+            length,
+            heights: vec![0.0; width * length],
+            valid: vec![false; width * length],
+            original: Vec::new(),
+        };
+
+        for (chunk, position) in chunks.iter() {
+            let offset_x = (position.x.round() as i32 - min_x) as usize;
+            let offset_z = (position.z.round() as i32 - min_z) as usize;
+
+            for x in 0..chunk_settings.width {
+                for z in 0..chunk_settings.length {
+                    if let Some(height) = surface_height(chunk_settings, chunk, x, z) {
+                        let height = position.y + height;
+                        let index = heightmap.index(offset_x + x, offset_z + z);
+                        if !heightmap.valid[index] || height > heightmap.heights[index] {
+                            heightmap.heights[index] = height;
+                            heightmap.valid[index] = true;
+                        }
+                    }
+                }
+            }
+        }
+
+        heightmap.original = heightmap.heights.clone();
+        Some(heightmap)
+    }
+
+    pub fn index(&self, x: usize, z: usize) -> usize {
+        z * self.width + x
+    }
+
+    pub fn get(&self, x: usize, z: usize) -> f32 {
+        self.heights[self.index(x, z)]
+    }
+
+    pub fn add(&mut self, x: usize, z: usize, amount: f32) {
+        let index = self.index(x, z);
+        self.heights[index] += amount;
+    }
+
+    /// Bilinear height and gradient at a point inside the grid, `None` if any
+    /// of the surrounding columns has no surface.
+    pub fn height_and_gradient(&self, pos_x: f32, pos_z: f32) -> Option<(f32, f32, f32)> {
+        let node_x = pos_x as usize;
+        let node_z = pos_z as usize;
+
+        if node_x + 1 >= self.width || node_z + 1 >= self.length {
+            return None;
+        }
+
+        let corners = [
+            self.index(node_x, node_z),
+            self.index(node_x + 1, node_z),
+            self.index(node_x, node_z + 1),
+            self.index(node_x + 1, node_z + 1),
+        ];
+
+        if corners.iter().any(|index| !self.valid[*index]) {
+            return None;
+        }
+
+        let nw = self.heights[corners[0]];
+        let ne = self.heights[corners[1]];
+        let sw = self.heights[corners[2]];
+        let se = self.heights[corners[3]];
+
+        let x = pos_x - node_x as f32;
+        let z = pos_z - node_z as f32;
+
+        let gradient_x = (ne - nw) * (1.0 - z) + (se - sw) * z;
+        let gradient_z = (sw - nw) * (1.0 - x) + (se - ne) * x;
+        let height = nw * (1.0 - x) * (1.0 - z) + ne * x * (1.0 - z) + sw * (1.0 - x) * z + se * x * z;
+
+        Some((height, gradient_x, gradient_z))
+    }
+
+    /// Rewrites the densities around every column whose surface moved. Samples
+    /// shared by neighbouring chunks get the same values in each of them.
+    /// Chunks outside the heightmap, e.g. loaded after it was taken, are left
+    /// alone. Returns the keys of the chunks it changed.
+    pub fn write_to_chunks<'b, K, C: 'b + DerefMut<Target = Chunk>>(
+        &self,
+        chunk_settings: &ChunkSettings,
+        chunks: impl Iterator<Item = (K, &'b mut C, Vec3)>,
+    ) -> Vec<K> {
+        let mut changed = Vec::new();
+        for (key, chunk, position) in chunks {
+            let offset_x = position.x.round() as i32 - self.origin_x;
+            let offset_z = position.z.round() as i32 - self.origin_z;
+            if offset_x < 0
+                || offset_z < 0
+                || offset_x as usize + chunk_settings.width > self.width
+                || offset_z as usize + chunk_settings.length > self.length
+            {
+                continue;
+            }
+            let (offset_x, offset_z) = (offset_x as usize, offset_z as usize);
+
+            let mut moved = false;
+            for x in 0..chunk_settings.width {
+                for z in 0..chunk_settings.length {
+                    let index = self.index(offset_x + x, offset_z + z);
+                    if !self.valid[index] {
+                        continue;
+                    }
+
+                    let old_height = self.original[index] - position.y;
+                    let new_height = self.heights[index] - position.y;
+                    if (new_height - old_height).abs() < 0.001 {
+                        continue;
+                    }
+
+                    let low = (old_height.min(new_height).floor() as i32 - 1).max(0);
+                    let high = (old_height.max(new_height).ceil() as i32 + 1).min(chunk_settings.height as i32 - 1);
+
+                    for y in low..=high {
+                        chunk.data[x][y as usize][z] =
+                            chunk_settings.threshold + (y as f32 - new_height).max(-1.0).min(1.0);
+                    }
+                    moved = true;
+                }
+            }
+
+            if moved {
+                changed.push(key);
+            }
+        }
+
+        changed
+    }
+}
+
+/// Height of the topmost solid to air crossing in a chunk column, relative to
+/// the chunk.
+pub fn surface_height(chunk_settings: &ChunkSettings, chunk: &Chunk, x: usize, z: usize) -> Option<f32> {
+    for y in (0..chunk_settings.height - 1).rev() {
+        let below = chunk.data[x][y][z];
+        let above = chunk.data[x][y + 1][z];
+
+        if below <= chunk_settings.threshold && above > chunk_settings.threshold {
+            let t = (chunk_settings.threshold - below) / (above - below);
+            return Some(y as f32 + t);
+        }
+    }
+
+    None
+}
+
+/// Hydraulic erosion in progress on the compute pool.
+#[derive(Default)]
+pub struct HydraulicErosionTask {
+    task: Option<Task<SurfaceHeightmap>>,
+    /// Chunks the surface was taken from.
+    chunks: HashSet<ChunkCoord>,
+    /// One of them changed while the task ran, writing the result back would
+    /// undo that change.
+    stale: bool,
+}
+
+/// Marks the running hydraulic erosion stale once any chunk it took its
+/// surface from changes. Runs after the edit stages so it sees their changes.
+fn track_eroded_chunks(
+    mut erosion_task: ResMut<HydraulicErosionTask>,
+    chunk_query: Query<&ChunkCoord, Mutated<Chunk>>,
+) {
+    if erosion_task.task.is_none() || erosion_task.stale {
+        return;
+    }
+
+    if chunk_query.iter().any(|coord| erosion_task.chunks.contains(coord)) {
+        erosion_task.stale = true;
+    }
+}
+
+/// H erodes the loaded terrain in the background. The surface is taken when
+/// the key is pressed and written back once every droplet has run, unless
+/// the terrain was edited in the meantime. Network clients leave erosion to
+/// the server.
+#[allow(clippy::too_many_arguments)] // Bevy systems take their resources as parameters
+fn hydraulic_erosion_system(
+    authority: Res<TerrainAuthority>,
+    keyboard_input: Res<Input<KeyCode>>,
+    erosion: Res<HydraulicErosion>,
+    chunk_settings: Res<ChunkSettings>,
+    task_pool: Res<AsyncComputeTaskPool>,
+    mut erosion_task: ResMut<HydraulicErosionTask>,
+    mut rewritten_events: ResMut<Events<ChunksRewritten>>,
+    mut chunk_query: Query<(&mut Chunk, &ChunkCoord)>,
+) {
+    if let Some(task) = erosion_task.task.as_mut() {
+        let heightmap = match future::block_on(future::poll_once(task)) {
+            Some(heightmap) => heightmap,
+            None => return,
+        };
+        erosion_task.task = None;
+        erosion_task.chunks.clear();
+        if erosion_task.stale {
+            warn!("the terrain changed while hydraulic erosion ran, dropping its result");
+            return;
+        }
+
+        let mut chunks: Vec<(ChunkCoord, Mut<Chunk>, Vec3)> = chunk_query
+            .iter_mut()
+            .map(|(chunk, coord)| (*coord, chunk, coord.origin(&chunk_settings)))
+            .collect();
+        let changed = heightmap.write_to_chunks(
+            &chunk_settings,
+            chunks
+                .iter_mut()
+                .map(|(coord, chunk, position)| (*coord, chunk, *position)),
+        );
+        if !changed.is_empty() {
+            rewritten_events.send(ChunksRewritten {
+                chunks: changed,
+                regenerated: false,
+            });
+        }
+        return;
+    }
+
+    if !authority.local || !keyboard_input.just_pressed(KeyCode::H) {
+        return;
+    }
+
+    let heightmap = SurfaceHeightmap::from_chunks(
+        &chunk_settings,
+        chunk_query
+            .iter()
+            .map(|(chunk, coord)| (chunk, coord.origin(&chunk_settings))),
+    );
+
+    if let Some(mut heightmap) = heightmap {
+        erosion_task.chunks = chunk_query.iter().map(|(_, coord)| *coord).collect();
+        erosion_task.stale = false;
+
+        let erosion = erosion.clone();
+        erosion_task.task = Some(task_pool.spawn(async move {
+            erosion.erode(&mut heightmap);
+            heightmap
+        }));
+    }
+}
+
+/// Chunks changed since the last background thermal pass.
+#[derive(Default)]
+pub struct ThermalSettling {
+    active: HashSet<ChunkCoord>,
+}
+
+/// Marks loaded and edited chunks for the next background thermal pass,
+/// including the ones the last pass changed, until the terrain comes to rest.
+fn track_settling_chunks(
+    erosion: Res<ThermalErosion>,
+    mut settling: ResMut<ThermalSettling>,
+    chunk_query: Query<&ChunkCoord, Changed<Chunk>>,
+) {
+    if erosion.continuous {
+        settling.active.extend(chunk_query.iter().copied());
+    }
+}
+
+/// T settles every loaded chunk, Shift+T toggles settling in the background.
+/// A background pass only covers the chunks around those that changed since
+/// the previous one.
+#[allow(clippy::too_many_arguments)] // Bevy systems take their resources as parameters
+fn thermal_erosion_system(
+    authority: Res<TerrainAuthority>,
+    time: Res<Time>,
+    keyboard_input: Res<Input<KeyCode>>,
+    mut erosion: ResMut<ThermalErosion>,
+    mut settling: ResMut<ThermalSettling>,
+    mut since_last_pass: Local<f32>,
+    chunk_settings: Res<ChunkSettings>,
+    mut rewritten_events: ResMut<Events<ChunksRewritten>>,
+    mut chunk_query: Query<(&mut Chunk, &ChunkCoord)>,
+) {
+    if !authority.local {
+        return;
+    }
+
+    let shift = keyboard_input.pressed(KeyCode::LShift) || keyboard_input.pressed(KeyCode::RShift);
+    if shift && keyboard_input.just_pressed(KeyCode::T) {
+        erosion.continuous = !erosion.continuous;
+        settling.active.clear();
+        if erosion.continuous {
+            settling.active.extend(chunk_query.iter().map(|(_, coord)| *coord));
+        }
+        return;
+    }
+
+    let full_pass = keyboard_input.just_pressed(KeyCode::T);
+    let iterations = if full_pass {
+        erosion.iterations
+    } else if erosion.continuous {
+        *since_last_pass += time.delta_seconds();
+        if *since_last_pass < erosion.interval {
+            return;
+        }
+        *since_last_pass = 0.0;
+        if settling.active.is_empty() {
+            return;
+        }
+        1
+    } else {
+        return;
+    };
+
+    // Every chunk of a column is needed to find its surface, and material
+    // slides across the border into the neighbouring columns.
+    let columns: HashSet<(i32, i32)> = settling
+        .active
+        .drain()
+        .flat_map(|coord| {
+            (-1..=1).flat_map(move |offset_x| {
+                (-1..=1).map(move |offset_z| (coord.x + offset_x, coord.z + offset_z))
+            })
+        })
+        .collect();
+
+    let mut chunks: Vec<(ChunkCoord, Mut<Chunk>, Vec3)> = chunk_query
+        .iter_mut()
+        .filter(|(_, coord)| full_pass || columns.contains(&(coord.x, coord.z)))
+        .map(|(chunk, coord)| (*coord, chunk, coord.origin(&chunk_settings)))
+        .collect();
+
+    let heightmap = SurfaceHeightmap::from_chunks(
+        &chunk_settings,
+        chunks.iter().map(|(_, chunk, position)| (&**chunk, *position)),
+    );
+
+    if let Some(mut heightmap) = heightmap {
+        erosion.erode_iterations(&mut heightmap, iterations);
+        let changed = heightmap.write_to_chunks(
+            &chunk_settings,
+            chunks
+                .iter_mut()
+                .map(|(coord, chunk, position)| (*coord, chunk, *position)),
+        );
+        if !changed.is_empty() {
+            rewritten_events.send(ChunksRewritten {
+                chunks: changed,
+                regenerated: false,
+            });
+        }
+    }
+}
+
+pub struct ErosionPlugin;
+
+impl Plugin for ErosionPlugin {
+    fn build(&self, app: &mut AppBuilder) {
+        app.init_resource::<HydraulicErosion>()
+            .init_resource::<HydraulicErosionTask>()
+            .init_resource::<ThermalErosion>()
+            .init_resource::<ThermalSettling>()
+            .add_system(hydraulic_erosion_system.system())
+            .add_system_to_stage(stage::POST_UPDATE, track_eroded_chunks.system())
+            .add_system(thermal_erosion_system.system())
+            .add_system_to_stage(stage::POST_UPDATE, track_settling_chunks.system());
+    }
+}
@@ -0,0 +1,176 @@
+use super::SurfaceHeightmap;
+
+const NEIGHBOURS: [(i32, i32); 8] = [
+    (-1, -1),
+    (0, -1),
+    (1, -1),
+    (-1, 0),
+    (1, 0),
+    (-1, 1),
+    (0, 1),
+    (1, 1),
+];
+
+/// Thermal weathering. Wherever the slope to a neighbouring column is steeper
+/// than the talus angle, part of the excess material slides down to it.
+pub struct ThermalErosion {
+    /// Steepest stable slope in degrees.
+    pub talus_angle: f32,
+    /// Share of half the steepest excess moved per iteration. At 1.0 a column
+    /// and its steepest neighbour meet halfway.
+    pub rate: f32,
+    pub iterations: usize,
+    /// Keep settling the terrain in the background instead of only on demand.
+    pub continuous: bool,
+    /// Seconds between background passes.
+    pub interval: f32,
+}
+
+impl Default for ThermalErosion {
+    fn default() -> Self {
+        Self {
+            talus_angle: 40.0,
+            rate: 0.5,
+            iterations: 20,
+            continuous: false,
+            interval: 0.5,
+        }
+    }
+}
+
+impl ThermalErosion {
+    pub fn erode(&self, heightmap: &mut SurfaceHeightmap) {
+        self.erode_iterations(heightmap, self.iterations);
+    }
+
+    pub fn erode_iterations(&self, heightmap: &mut SurfaceHeightmap, iterations: usize) {
+        let talus = self.talus_angle.to_radians().tan();
+        let mut deltas = vec![0f32; heightmap.heights.len()];
+
+        for _ in 0..iterations {
+            for delta in deltas.iter_mut() {
+                *delta = 0.0;
+            }
+
+            for z in 0..heightmap.length {
+                for x in 0..heightmap.width {
+                    let index = heightmap.index(x, z);
+                    if !heightmap.valid[index] {
+                        continue;
+                    }
+
+                    let height = heightmap.heights[index];
+                    let mut lower: [(usize, f32); 8] = [(0, 0.0); 8];
+                    let mut lower_count = 0;
+                    let mut total_excess = 0f32;
+                    let mut max_excess = 0f32;
+
+                    for (offset_x, offset_z) in NEIGHBOURS.iter() {
+                        let neighbour_x = x as i32 + offset_x;
+                        let neighbour_z = z as i32 + offset_z;
+                        if neighbour_x < 0
+                            || neighbour_z < 0
+                            || neighbour_x as usize >= heightmap.width
+                            || neighbour_z as usize >= heightmap.length
+                        {
+                            continue;
+                        }
+
+                        let neighbour = heightmap.index(neighbour_x as usize, neighbour_z as usize);
+                        if !heightmap.valid[neighbour] {
+                            continue;
+                        }
+
+                        let distance = ((offset_x * offset_x + offset_z * offset_z) as f32).sqrt();
+                        let excess = height - heightmap.heights[neighbour] - talus * distance;
+                        if excess > 0.0 {
+                            lower[lower_count] = (neighbour, excess);
+                            lower_count += 1;
+                            total_excess += excess;
+                            max_excess = max_excess.max(excess);
+                        }
+                    }
+
+                    if lower_count == 0 {
+                        continue;
+                    }
+
+                    // Never move more than half the steepest drop, otherwise the
+                    // two columns just swap places and the slope oscillates.
+                    let moved = max_excess * 0.5 * self.rate;
+                    deltas[index] -= moved;
+                    for (neighbour, excess) in lower.iter().take(lower_count) {
+                        deltas[*neighbour] += moved * excess / total_excess;
+                    }
+                }
+            }
+
+            for (height, delta) in heightmap.heights.iter_mut().zip(deltas.iter()) {
+                *height += delta;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::math::Vec3;
+
+    use super::*;
+    use crate::chunk::{test_util::settings, Chunk};
+
+    fn stepped_heightmap() -> SurfaceHeightmap {
+        let chunk_settings = settings();
+        let mut chunk = Chunk::new(&chunk_settings);
+        for x in 0..16 {
+            for y in 0..16 {
+                for z in 0..16 {
+                    let surface = if x < 8 { 4.0 } else { 12.0 };
+                    chunk.data[x][y][z] = (y as f32 - surface).max(-1.0).min(1.0);
+                }
+            }
+        }
+
+        SurfaceHeightmap::from_chunks(&chunk_settings, std::iter::once((&chunk, Vec3::zero()))).unwrap()
+    }
+
+    #[test]
+    fn steep_slopes_relax_to_the_talus_angle() {
+        let erosion = ThermalErosion::default();
+        let mut heightmap = stepped_heightmap();
+        erosion.erode_iterations(&mut heightmap, 500);
+
+        let talus = erosion.talus_angle.to_radians().tan();
+        for z in 0..heightmap.length {
+            for x in 0..heightmap.width {
+                for (offset_x, offset_z) in NEIGHBOURS.iter() {
+                    let neighbour_x = x as i32 + offset_x;
+                    let neighbour_z = z as i32 + offset_z;
+                    if neighbour_x < 0
+                        || neighbour_z < 0
+                        || neighbour_x as usize >= heightmap.width
+                        || neighbour_z as usize >= heightmap.length
+                    {
+                        continue;
+                    }
+
+                    let distance = ((offset_x * offset_x + offset_z * offset_z) as f32).sqrt();
+                    let drop = heightmap.get(x, z) - heightmap.get(neighbour_x as usize, neighbour_z as usize);
+                    assert!(drop <= talus * distance + 1e-3, "drop of {} at {}, {}", drop, x, z);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn same_terrain_settles_the_same() {
+        let erosion = ThermalErosion::default();
+        let mut first = stepped_heightmap();
+        let mut second = stepped_heightmap();
+        erosion.erode(&mut first);
+        erosion.erode(&mut second);
+
+        assert_eq!(first.heights, second.heights);
+        assert_ne!(first.heights, stepped_heightmap().heights);
+    }
+}
@@ -0,0 +1,485 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::path::PathBuf;
+
+use bevy::{
+    asset::{AssetLoader, LoadContext, LoadedAsset},
+    prelude::*,
+    reflect::TypeUuid,
+    utils::BoxedFuture,
+};
+use noise::{Fbm, MultiFractal, NoiseFn, Seedable};
+use serde::Deserialize;
+
+use super::heightmap::{Heightmap, HeightmapFormat, HeightmapImport};
+
+/// Terrain generator described as a graph of named nodes. The value of the
+/// `output` node is the density, negative inside the ground.
+#[derive(Deserialize, TypeUuid)]
+#[uuid = "6c3f5a0e-5b0d-4c4b-9a59-3d2f64f1c0a7"]
+pub struct GeneratorGraph {
+    pub output: String,
+    pub nodes: HashMap<String, GeneratorNode>,
+}
+
+#[derive(Deserialize, Clone)]
+pub enum GeneratorNode {
+    Constant(f32),
+    PositionX,
+    PositionY,
+    PositionZ,
+    Noise {
+        seed: u32,
+        frequency: f64,
+        #[serde(default = "default_octaves")]
+        octaves: usize,
+        #[serde(default = "default_amplitude")]
+        amplitude: f32,
+    },
+    Add(String, String),
+    Sub(String, String),
+    Mul(String, String),
+    Min(String, String),
+    Max(String, String),
+    Neg(String),
+    Abs(String),
+    Clamp {
+        input: String,
+        min: f32,
+        max: f32,
+    },
+    /// Piecewise linear remap through `(input, output)` points.
+    Curve {
+        input: String,
+        points: Vec<(f32, f32)>,
+    },
+    Sphere {
+        center: (f32, f32, f32),
+        radius: f32,
+    },
+    Box {
+        center: (f32, f32, f32),
+        half_extents: (f32, f32, f32),
+    },
+    /// Height of a heightmap image at the sample's x/z, path relative to the
+    /// assets folder. A `.png` is read with its own size, any other file as
+    /// raw 16-bit samples that have to form a square, since raw files carry
+    /// no size. The file isn't an asset of its own, see `heightmap_paths`.
+    Heightmap {
+        path: String,
+        horizontal_scale: f32,
+        vertical_scale: f32,
+        #[serde(default)]
+        base_height: f32,
+    },
+    /// Picks the input whose threshold is the highest one below the selector
+    /// value, blending across `blend` units around each threshold.
+    BiomeSelect {
+        selector: String,
+        biomes: Vec<(f32, String)>,
+        #[serde(default)]
+        blend: f32,
+    },
+}
+
+fn default_octaves() -> usize {
+    4
+}
+
+fn default_amplitude() -> f32 {
+    1.0
+}
+
+#[derive(Debug)]
+pub enum GraphError {
+    UnknownNode(String),
+    Cycle(String),
+    EmptyBiomeSelect(String),
+    Heightmap(String, super::HeightmapError),
+}
+
+impl fmt::Display for GraphError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            GraphError::UnknownNode(name) => write!(f, "generator graph references unknown node `{}`", name),
+            GraphError::Cycle(name) => write!(f, "generator graph has a cycle through `{}`", name),
+            GraphError::EmptyBiomeSelect(name) => write!(f, "biome selector `{}` has no biomes", name),
+            GraphError::Heightmap(name, err) => write!(f, "heightmap node `{}`: {}", name, err),
+        }
+    }
+}
+
+impl std::error::Error for GraphError {}
+
+enum CompiledNode {
+    Constant(f32),
+    PositionX,
+    PositionY,
+    PositionZ,
+    Noise(Fbm, f32),
+    Add(usize, usize),
+    Sub(usize, usize),
+    Mul(usize, usize),
+    Min(usize, usize),
+    Max(usize, usize),
+    Neg(usize),
+    Abs(usize),
+    Clamp(usize, f32, f32),
+    Curve(usize, Vec<(f32, f32)>),
+    Sphere(Vec3, f32),
+    Box(Vec3, Vec3),
+    Heightmap(Heightmap),
+    BiomeSelect(usize, Vec<(f32, usize)>, f32),
+}
+
+/// A generator graph with names resolved to indices, ready to be sampled.
+pub struct CompiledGraph {
+    nodes: Vec<CompiledNode>,
+    output: usize,
+}
+
+struct Compiler<'a> {
+    graph: &'a GeneratorGraph,
+    indices: HashMap<&'a str, usize>,
+    visiting: Vec<&'a str>,
+    nodes: Vec<CompiledNode>,
+}
+
+impl<'a> Compiler<'a> {
+    fn compile(&mut self, name: &'a str) -> Result<usize, GraphError> {
+        if let Some(index) = self.indices.get(name) {
+            return Ok(*index);
+        }
+
+        if self.visiting.contains(&name) {
+            return Err(GraphError::Cycle(name.to_string()));
+        }
+
+        let graph = self.graph;
+        let node = graph
+            .nodes
+            .get(name)
+            .ok_or_else(|| GraphError::UnknownNode(name.to_string()))?;
+
+        self.visiting.push(name);
+        let compiled = match node {
+            GeneratorNode::Constant(value) => CompiledNode::Constant(*value),
+            GeneratorNode::PositionX => CompiledNode::PositionX,
+            GeneratorNode::PositionY => CompiledNode::PositionY,
+            GeneratorNode::PositionZ => CompiledNode::PositionZ,
+            GeneratorNode::Noise {
+                seed,
+                frequency,
+                octaves,
+                amplitude,
+            } => CompiledNode::Noise(
+                Fbm::new()
+                    .set_seed(*seed)
+                    .set_octaves((*octaves).max(1).min(Fbm::MAX_OCTAVES))
+                    .set_frequency(*frequency),
+                *amplitude,
+            ),
+            GeneratorNode::Add(a, b) => CompiledNode::Add(self.compile(a)?, self.compile(b)?),
+            GeneratorNode::Sub(a, b) => CompiledNode::Sub(self.compile(a)?, self.compile(b)?),
+            GeneratorNode::Mul(a, b) => CompiledNode::Mul(self.compile(a)?, self.compile(b)?),
+            GeneratorNode::Min(a, b) => CompiledNode::Min(self.compile(a)?, self.compile(b)?),
+            GeneratorNode::Max(a, b) => CompiledNode::Max(self.compile(a)?, self.compile(b)?),
+            GeneratorNode::Neg(input) => CompiledNode::Neg(self.compile(input)?),
+            GeneratorNode::Abs(input) => CompiledNode::Abs(self.compile(input)?),
+            GeneratorNode::Clamp { input, min, max } => CompiledNode::Clamp(self.compile(input)?, *min, *max),
+            GeneratorNode::Curve { input, points } => {
+                let mut points = points.clone();
+                points.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap_or(std::cmp::Ordering::Equal));
+                CompiledNode::Curve(self.compile(input)?, points)
+            }
+            GeneratorNode::Sphere { center, radius } => {
+                CompiledNode::Sphere(Vec3::new(center.0, center.1, center.2), *radius)
+            }
+            GeneratorNode::Box { center, half_extents } => CompiledNode::Box(
+                Vec3::new(center.0, center.1, center.2),
+                Vec3::new(half_extents.0, half_extents.1, half_extents.2),
+            ),
+            GeneratorNode::Heightmap {
+                path,
+                horizontal_scale,
+                vertical_scale,
+                base_height,
+            } => {
+                let format = if path.ends_with(".png") {
+                    HeightmapFormat::Png
+                } else {
+                    let side = raw_side_length(path)
+                        .map_err(|err| GraphError::Heightmap(name.to_string(), err.into()))?;
+                    HeightmapFormat::Raw {
+                        width: side,
+                        length: side,
+                    }
+                };
+
+                let import = HeightmapImport {
+                    path: PathBuf::from("assets").join(path),
+                    format,
+                    horizontal_scale: *horizontal_scale,
+                    vertical_scale: *vertical_scale,
+                    base_height: *base_height,
+                };
+
+                CompiledNode::Heightmap(
+                    import
+                        .load()
+                        .map_err(|err| GraphError::Heightmap(name.to_string(), err))?,
+                )
+            }
+            GeneratorNode::BiomeSelect { selector, biomes, blend } => {
+                if biomes.is_empty() {
+                    return Err(GraphError::EmptyBiomeSelect(name.to_string()));
+                }
+
+                let selector = self.compile(selector)?;
+                let mut compiled_biomes = Vec::new();
+                for (threshold, input) in biomes.iter() {
+                    compiled_biomes.push((*threshold, self.compile(input)?));
+                }
+                compiled_biomes.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap_or(std::cmp::Ordering::Equal));
+
+                CompiledNode::BiomeSelect(selector, compiled_biomes, *blend)
+            }
+        };
+        self.visiting.pop();
+
+        let index = self.nodes.len();
+        self.nodes.push(compiled);
+        self.indices.insert(name, index);
+        Ok(index)
+    }
+}
+
+/// Raw heightmaps carry no header, so graph nodes only accept square ones.
+/// The side is taken from the file size, a file that isn't square fails to
+/// load with `HeightmapError::InvalidSize`.
+fn raw_side_length(path: &str) -> std::io::Result<usize> {
+    let bytes = std::fs::metadata(PathBuf::from("assets").join(path))?.len();
+    Ok(((bytes / 2) as f64).sqrt() as usize)
+}
+
+impl GeneratorGraph {
+    /// Files the heightmap nodes read while compiling. They're read directly
+    /// rather than through the asset server, so changes to them have to be
+    /// watched for separately.
+    pub fn heightmap_paths(&self) -> Vec<PathBuf> {
+        self.nodes
+            .values()
+            .filter_map(|node| match node {
+                GeneratorNode::Heightmap { path, .. } => Some(PathBuf::from("assets").join(path)),
+                _ => None,
+            })
+            .collect()
+    }
+
+    pub fn compile(&self) -> Result<CompiledGraph, GraphError> {
+        let mut compiler = Compiler {
+            graph: self,
+            indices: HashMap::new(),
+            visiting: Vec::new(),
+            nodes: Vec::new(),
+        };
+
+        let output = compiler.compile(&self.output)?;
+
+        Ok(CompiledGraph {
+            nodes: compiler.nodes,
+            output,
+        })
+    }
+}
+
+impl CompiledGraph {
+    pub fn density(&self, threshold: f32, position: Vec3) -> f32 {
+        threshold + self.evaluate(self.output, position).max(-1.0).min(1.0)
+    }
+
+    fn evaluate(&self, index: usize, position: Vec3) -> f32 {
+        match &self.nodes[index] {
+            CompiledNode::Constant(value) => *value,
+            CompiledNode::PositionX => position.x,
+            CompiledNode::PositionY => position.y,
+            CompiledNode::PositionZ => position.z,
+            CompiledNode::Noise(noise, amplitude) => {
+                noise.get([position.x as f64, position.y as f64, position.z as f64]) as f32 * amplitude
+            }
+            CompiledNode::Add(a, b) => self.evaluate(*a, position) + self.evaluate(*b, position),
+            CompiledNode::Sub(a, b) => self.evaluate(*a, position) - self.evaluate(*b, position),
+            CompiledNode::Mul(a, b) => self.evaluate(*a, position) * self.evaluate(*b, position),
+            CompiledNode::Min(a, b) => self.evaluate(*a, position).min(self.evaluate(*b, position)),
+            CompiledNode::Max(a, b) => self.evaluate(*a, position).max(self.evaluate(*b, position)),
+            CompiledNode::Neg(input) => -self.evaluate(*input, position),
+            CompiledNode::Abs(input) => self.evaluate(*input, position).abs(),
+            CompiledNode::Clamp(input, min, max) => self.evaluate(*input, position).max(*min).min(*max),
+            CompiledNode::Curve(input, points) => evaluate_curve(points, self.evaluate(*input, position)),
+            CompiledNode::Sphere(center, radius) => (position - *center).length() - radius,
+            CompiledNode::Box(center, half_extents) => {
+                let q = (position - *center).abs() - *half_extents;
+                q.max(Vec3::zero()).length() + q.x.max(q.y.max(q.z)).min(0.0)
+            }
+            CompiledNode::Heightmap(heightmap) => heightmap.height_at(position.x, position.z),
+            CompiledNode::BiomeSelect(selector, biomes, blend) => {
+                let selector = self.evaluate(*selector, position);
+                let current = biomes
+                    .iter()
+                    .rposition(|(threshold, _)| selector >= *threshold)
+                    .unwrap_or(0);
+                let mut value = self.evaluate(biomes[current].1, position);
+
+                if *blend > 0.0 {
+                    if current + 1 < biomes.len() {
+                        let boundary = biomes[current + 1].0;
+                        let weight = ((selector - (boundary - blend * 0.5)) / blend).max(0.0).min(1.0);
+                        if weight > 0.0 {
+                            value += (self.evaluate(biomes[current + 1].1, position) - value) * weight;
+                        }
+                    }
+
+                    if current > 0 {
+                        let boundary = biomes[current].0;
+                        let weight = ((selector - (boundary - blend * 0.5)) / blend).max(0.0).min(1.0);
+                        if weight < 1.0 {
+                            let previous = self.evaluate(biomes[current - 1].1, position);
+                            value = previous + (value - previous) * weight;
+                        }
+                    }
+                }
+
+                value
+            }
+        }
+    }
+}
+
+fn evaluate_curve(points: &[(f32, f32)], input: f32) -> f32 {
+    if points.is_empty() {
+        return input;
+    }
+
+    if input <= points[0].0 {
+        return points[0].1;
+    }
+
+    for window in points.windows(2) {
+        let (x0, y0) = window[0];
+        let (x1, y1) = window[1];
+        if input <= x1 {
+            let t = if x1 > x0 { (input - x0) / (x1 - x0) } else { 1.0 };
+            return y0 + (y1 - y0) * t;
+        }
+    }
+
+    points[points.len() - 1].1
+}
+
+#[derive(Default)]
+pub struct GeneratorGraphLoader;
+
+impl AssetLoader for GeneratorGraphLoader {
+    fn load<'a>(
+        &'a self,
+        bytes: &'a [u8],
+        load_context: &'a mut LoadContext,
+    ) -> BoxedFuture<'a, Result<(), anyhow::Error>> {
+        Box::pin(async move {
+            let graph = ron::de::from_bytes::<GeneratorGraph>(bytes)?;
+            load_context.set_default_asset(LoadedAsset::new(graph));
+            Ok(())
+        })
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &["ron"]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn graph(output: &str, nodes: Vec<(&str, GeneratorNode)>) -> GeneratorGraph {
+        GeneratorGraph {
+            output: output.to_string(),
+            nodes: nodes.into_iter().map(|(name, node)| (name.to_string(), node)).collect(),
+        }
+    }
+
+    #[test]
+    fn plane_graph_is_solid_below_its_height() {
+        let compiled = graph(
+            "ground",
+            vec![
+                ("y", GeneratorNode::PositionY),
+                ("height", GeneratorNode::Constant(4.0)),
+                ("ground", GeneratorNode::Sub("y".to_string(), "height".to_string())),
+            ],
+        )
+        .compile()
+        .unwrap();
+
+        assert!(compiled.density(0.0, Vec3::new(0.0, 2.0, 0.0)) < 0.0);
+        assert!(compiled.density(0.0, Vec3::new(0.0, 6.0, 0.0)) > 0.0);
+        assert_eq!(compiled.density(0.0, Vec3::new(0.0, 100.0, 0.0)), 1.0);
+    }
+
+    #[test]
+    fn unknown_node_is_an_error() {
+        let result = graph("ground", vec![("ground", GeneratorNode::Neg("missing".to_string()))]).compile();
+        assert!(matches!(result, Err(GraphError::UnknownNode(name)) if name == "missing"));
+    }
+
+    #[test]
+    fn cycle_is_an_error() {
+        let result = graph(
+            "a",
+            vec![
+                ("a", GeneratorNode::Neg("b".to_string())),
+                ("b", GeneratorNode::Abs("a".to_string())),
+            ],
+        )
+        .compile();
+        assert!(matches!(result, Err(GraphError::Cycle(_))));
+    }
+
+    #[test]
+    fn biome_select_needs_a_biome() {
+        let result = graph(
+            "ground",
+            vec![
+                ("x", GeneratorNode::PositionX),
+                (
+                    "ground",
+                    GeneratorNode::BiomeSelect {
+                        selector: "x".to_string(),
+                        biomes: Vec::new(),
+                        blend: 0.0,
+                    },
+                ),
+            ],
+        )
+        .compile();
+        assert!(matches!(result, Err(GraphError::EmptyBiomeSelect(_))));
+    }
+
+    #[test]
+    fn missing_raw_heightmap_is_an_error() {
+        let result = graph(
+            "ground",
+            vec![(
+                "ground",
+                GeneratorNode::Heightmap {
+                    path: "does/not/exist.raw".to_string(),
+                    horizontal_scale: 1.0,
+                    vertical_scale: 1.0,
+                    base_height: 0.0,
+                },
+            )],
+        )
+        .compile();
+        assert!(matches!(result, Err(GraphError::Heightmap(_, _))));
+    }
+}
@@ -0,0 +1,240 @@
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+pub enum HeightmapFormat {
+    /// 8 or 16-bit grayscale PNG, the size is read from the file.
+    Png,
+    /// Headerless little-endian 16-bit samples, row by row.
+    Raw { width: usize, length: usize },
+}
+
+pub struct HeightmapImport {
+    pub path: PathBuf,
+    pub format: HeightmapFormat,
+    /// World units between two neighbouring pixels.
+    pub horizontal_scale: f32,
+    /// World height of a full white pixel.
+    pub vertical_scale: f32,
+    /// World height of a black pixel.
+    pub base_height: f32,
+}
+
+#[derive(Debug)]
+pub enum HeightmapError {
+    Io(io::Error),
+    Image(image::ImageError),
+    InvalidSize { expected: usize, actual: usize },
+    Empty,
+    InvalidScale(f32),
+}
+
+impl fmt::Display for HeightmapError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HeightmapError::Io(err) => write!(f, "failed to read heightmap: {}", err),
+            HeightmapError::Image(err) => write!(f, "failed to decode heightmap: {}", err),
+            HeightmapError::InvalidSize { expected, actual } => write!(
+                f,
+                "raw heightmap should be {} bytes but is {} bytes",
+                expected, actual
+            ),
+            HeightmapError::Empty => write!(f, "heightmap has no pixels"),
+            HeightmapError::InvalidScale(scale) => {
+                write!(f, "heightmap horizontal scale must be positive but is {}", scale)
+            }
+        }
+    }
+}
+
+impl std::error::Error for HeightmapError {}
+
+impl From<io::Error> for HeightmapError {
+    fn from(err: io::Error) -> Self {
+        HeightmapError::Io(err)
+    }
+}
+
+impl From<image::ImageError> for HeightmapError {
+    fn from(err: image::ImageError) -> Self {
+        HeightmapError::Image(err)
+    }
+}
+
+pub struct Heightmap {
+    pub width: usize,
+    pub length: usize,
+    /// Normalized samples in `[0, 1]`, row major with `x` varying fastest.
+    pub samples: Vec<f32>,
+    pub horizontal_scale: f32,
+    pub vertical_scale: f32,
+    pub base_height: f32,
+}
+
+impl HeightmapImport {
+    pub fn load(&self) -> Result<Heightmap, HeightmapError> {
+        // Sampling divides by the scale
+        if !(self.horizontal_scale.is_finite() && self.horizontal_scale > 0.0) {
+            return Err(HeightmapError::InvalidScale(self.horizontal_scale));
+        }
+
+        let (width, length, samples) = match self.format {
+            HeightmapFormat::Png => read_png(&self.path)?,
+            HeightmapFormat::Raw { width, length } => read_raw(&self.path, width, length)?,
+        };
+
+        // Sampling clamps to the last pixel, which needs at least one
+        if width == 0 || length == 0 {
+            return Err(HeightmapError::Empty);
+        }
+
+        Ok(Heightmap {
+            width,
+            length,
+            samples,
+            horizontal_scale: self.horizontal_scale,
+            vertical_scale: self.vertical_scale,
+            base_height: self.base_height,
+        })
+    }
+}
+
+fn read_png(path: &Path) -> Result<(usize, usize, Vec<f32>), HeightmapError> {
+    let image = image::open(path)?.to_luma16();
+    let (width, length) = image.dimensions();
+    let samples = image
+        .pixels()
+        .map(|pixel| pixel.0[0] as f32 / u16::MAX as f32)
+        .collect();
+
+    Ok((width as usize, length as usize, samples))
+}
+
+fn read_raw(path: &Path, width: usize, length: usize) -> Result<(usize, usize, Vec<f32>), HeightmapError> {
+    let bytes = fs::read(path)?;
+    let expected = width * length * 2;
+    if bytes.len() != expected {
+        return Err(HeightmapError::InvalidSize {
+            expected,
+            actual: bytes.len(),
+        });
+    }
+
+    let samples = bytes
+        .chunks_exact(2)
+        .map(|pair| u16::from_le_bytes([pair[0], pair[1]]) as f32 / u16::MAX as f32)
+        .collect();
+
+    Ok((width, length, samples))
+}
+
+impl Heightmap {
+    fn sample(&self, x: usize, z: usize) -> f32 {
+        self.samples[z * self.width + x]
+    }
+
+    /// World height at a world position, bilinearly filtered and clamped to
+    /// the edges of the image.
+    pub fn height_at(&self, x: f32, z: f32) -> f32 {
+        let max_x = (self.width - 1) as f32;
+        let max_z = (self.length - 1) as f32;
+        let pixel_x = (x / self.horizontal_scale).max(0.0).min(max_x);
+        let pixel_z = (z / self.horizontal_scale).max(0.0).min(max_z);
+
+        let x0 = pixel_x.floor() as usize;
+        let z0 = pixel_z.floor() as usize;
+        let x1 = (x0 + 1).min(self.width - 1);
+        let z1 = (z0 + 1).min(self.length - 1);
+        let tx = pixel_x - x0 as f32;
+        let tz = pixel_z - z0 as f32;
+
+        let top = self.sample(x0, z0) * (1.0 - tx) + self.sample(x1, z0) * tx;
+        let bottom = self.sample(x0, z1) * (1.0 - tx) + self.sample(x1, z1) * tx;
+
+        self.base_height + (top * (1.0 - tz) + bottom * tz) * self.vertical_scale
+    }
+
+    /// Signed vertical distance to the surface, negative below it.
+    pub fn distance(&self, x: f32, y: f32, z: f32) -> f32 {
+        y - self.height_at(x, z)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("heightmap-test-{}-{}", std::process::id(), name))
+    }
+
+    fn import(path: PathBuf, width: usize, length: usize) -> HeightmapImport {
+        HeightmapImport {
+            path,
+            format: HeightmapFormat::Raw { width, length },
+            horizontal_scale: 1.0,
+            vertical_scale: 10.0,
+            base_height: 0.0,
+        }
+    }
+
+    #[test]
+    fn raw_heightmap_is_sampled_bilinearly() {
+        let path = temp_path("bilinear.raw");
+        let samples: [u16; 4] = [0, u16::MAX, 0, u16::MAX];
+        let bytes: Vec<u8> = samples.iter().flat_map(|sample| sample.to_le_bytes().to_vec()).collect();
+        fs::write(&path, bytes).unwrap();
+
+        let heightmap = import(path.clone(), 2, 2).load().unwrap();
+        fs::remove_file(path).unwrap();
+
+        assert!((heightmap.height_at(0.0, 0.0) - 0.0).abs() < 1e-4);
+        assert!((heightmap.height_at(0.5, 0.0) - 5.0).abs() < 1e-4);
+        assert!((heightmap.height_at(5.0, 5.0) - 10.0).abs() < 1e-4);
+    }
+
+    #[test]
+    fn zero_sized_heightmap_is_rejected() {
+        let path = temp_path("empty.raw");
+        fs::write(&path, []).unwrap();
+
+        let result = import(path.clone(), 0, 0).load();
+        fs::remove_file(path).unwrap();
+
+        assert!(matches!(result, Err(HeightmapError::Empty)));
+    }
+
+    #[test]
+    fn non_positive_horizontal_scale_is_rejected() {
+        let path = temp_path("scale.raw");
+        fs::write(&path, [0u8; 8]).unwrap();
+
+        let results: Vec<_> = [0.0, -1.0, f32::NAN, f32::INFINITY]
+            .iter()
+            .map(|&horizontal_scale| {
+                HeightmapImport {
+                    horizontal_scale,
+                    ..import(path.clone(), 2, 2)
+                }
+                .load()
+            })
+            .collect();
+        fs::remove_file(path).unwrap();
+
+        for result in results {
+            assert!(matches!(result, Err(HeightmapError::InvalidScale(_))));
+        }
+    }
+
+    #[test]
+    fn wrong_raw_size_is_rejected() {
+        let path = temp_path("short.raw");
+        fs::write(&path, [0u8; 6]).unwrap();
+
+        let result = import(path.clone(), 2, 2).load();
+        fs::remove_file(path).unwrap();
+
+        assert!(matches!(result, Err(HeightmapError::InvalidSize { expected: 8, actual: 6 })));
+    }
+}
@@ -0,0 +1,245 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use bevy::prelude::*;
+use noise::{Fbm, MultiFractal, NoiseFn, Seedable};
+
+use crate::chunk::{map::ChunkCoord, Chunk, ChunkSettings, ChunksRewritten};
+use crate::persistence::EditJournal;
+use crate::sculpt::TerrainAuthority;
+
+pub mod graph;
+pub mod heightmap;
+
+pub use graph::{CompiledGraph, GeneratorGraph, GeneratorGraphLoader, GeneratorNode, GraphError};
+pub use heightmap::{Heightmap, HeightmapError, HeightmapFormat, HeightmapImport};
+
+/// Base shape the chunk densities are generated from. Noise or SDF edits are
+/// applied on top of the generated chunk afterwards.
+pub enum TerrainSource {
+    Plane { height: f32 },
+    /// An imported heightmap with `layers` applied over it in order.
+    Heightmap { heightmap: Heightmap, layers: Vec<TerrainLayer> },
+    Graph(CompiledGraph),
+}
+
+/// Detail layered over an imported heightmap, which on its own can't have
+/// overhangs. SDF shapes come from the sculpting edits replayed on top.
+pub enum TerrainLayer {
+    /// Noise added to the distance from the surface, `amplitude` units at
+    /// most, so it only reaches that far above or below the surface.
+    Noise { noise: Fbm, amplitude: f32 },
+}
+
+impl TerrainLayer {
+    pub fn noise(seed: u32, frequency: f64, octaves: usize, amplitude: f32) -> Self {
+        TerrainLayer::Noise {
+            noise: Fbm::new()
+                .set_seed(seed)
+                .set_octaves(octaves.max(1).min(Fbm::MAX_OCTAVES))
+                .set_frequency(frequency),
+            amplitude,
+        }
+    }
+
+    /// Applies the layer to the signed distance from the terrain surface at
+    /// `position`.
+    fn apply(&self, distance: f32, position: Vec3) -> f32 {
+        match self {
+            TerrainLayer::Noise { noise, amplitude } => {
+                distance + noise.get([position.x as f64, position.y as f64, position.z as f64]) as f32 * amplitude
+            }
+        }
+    }
+}
+
+impl Default for TerrainSource {
+    fn default() -> Self {
+        TerrainSource::Plane { height: 5.0 }
+    }
+}
+
+impl TerrainSource {
+    pub fn density(&self, threshold: f32, position: Vec3) -> f32 {
+        match self {
+            TerrainSource::Plane { height } => {
+                if position.y > *height {
+                    threshold + 1f32
+                } else {
+                    threshold - 1f32
+                }
+            }
+            TerrainSource::Heightmap { heightmap, layers } => {
+                let distance = layers.iter().fold(
+                    heightmap.distance(position.x, position.y, position.z),
+                    |distance, layer| layer.apply(distance, position),
+                );
+                // Clamped to one unit, the density range used by the rest of the terrain
+                threshold + distance.max(-1.0).min(1.0)
+            }
+            TerrainSource::Graph(graph) => graph.density(threshold, position),
+        }
+    }
+}
+
+pub fn generate_chunk(chunk_settings: &ChunkSettings, source: &TerrainSource, origin: Vec3) -> Chunk {
+    let mut chunk = Chunk::new(chunk_settings);
+
+    for x in 0..chunk_settings.width {
+        for y in 0..chunk_settings.height {
+            for z in 0..chunk_settings.length {
+                let position = origin + Vec3::new(x as f32, y as f32, z as f32);
+                chunk.data[x][y][z] = source.density(chunk_settings.threshold, position);
+            }
+        }
+    }
+
+    chunk
+}
+
+pub struct GeneratorSettings {
+    /// Generator graph to load, relative to the assets folder. The terrain
+    /// source already in place is used until it finishes loading.
+    pub graph_path: Option<String>,
+}
+
+impl Default for GeneratorSettings {
+    fn default() -> Self {
+        Self { graph_path: None }
+    }
+}
+
+/// Seconds between checks whether a heightmap the graph reads changed.
+const HEIGHTMAP_POLL_INTERVAL: f32 = 1.0;
+
+#[derive(Default)]
+pub struct GeneratorGraphState {
+    pub handle: Option<Handle<GeneratorGraph>>,
+    event_reader: EventReader<AssetEvent<GeneratorGraph>>,
+    /// Heightmap files of the current graph with their modification time
+    /// when it was compiled.
+    heightmaps: Vec<(PathBuf, Option<SystemTime>)>,
+}
+
+fn modified_time(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|metadata| metadata.modified()).ok()
+}
+
+fn load_generator_graph(
+    asset_server: Res<AssetServer>,
+    generator_settings: Res<GeneratorSettings>,
+    mut state: ResMut<GeneratorGraphState>,
+) {
+    if let Some(path) = &generator_settings.graph_path {
+        if let Err(err) = asset_server.watch_for_changes() {
+            warn!("generator graph won't hot reload: {:?}", err);
+        }
+        state.handle = Some(asset_server.load(path.as_str()));
+    }
+}
+
+/// Swaps in the generator graph whenever it or a heightmap it reads changes
+/// and regenerates every loaded chunk from it with the journal replayed on
+/// top. The regenerated chunks hold nothing else, so their saved copies are
+/// dropped instead of kept or rewritten. Network clients only swap the graph
+/// in, their loaded chunks are the server's.
+#[allow(clippy::too_many_arguments)] // Bevy systems take their resources as parameters
+fn apply_generator_graph(
+    authority: Res<TerrainAuthority>,
+    time: Res<Time>,
+    mut since_heightmap_check: Local<f32>,
+    mut state: ResMut<GeneratorGraphState>,
+    graph_events: Res<Events<AssetEvent<GeneratorGraph>>>,
+    graphs: Res<Assets<GeneratorGraph>>,
+    chunk_settings: Res<ChunkSettings>,
+    journal: Res<EditJournal>,
+    mut terrain_source: ResMut<TerrainSource>,
+    mut rewritten_events: ResMut<Events<ChunksRewritten>>,
+    mut chunk_query: Query<(&mut Chunk, &ChunkCoord)>,
+) {
+    let state = &mut *state;
+    let handle = match &state.handle {
+        Some(handle) => handle,
+        None => return,
+    };
+
+    let mut changed = false;
+    for event in state.event_reader.iter(&graph_events) {
+        match event {
+            AssetEvent::Created { handle: event_handle } | AssetEvent::Modified { handle: event_handle } => {
+                if event_handle == handle {
+                    changed = true;
+                }
+            }
+            AssetEvent::Removed { .. } => {}
+        }
+    }
+
+    *since_heightmap_check += time.delta_seconds();
+    if !changed && *since_heightmap_check >= HEIGHTMAP_POLL_INTERVAL {
+        *since_heightmap_check = 0.0;
+        changed = state
+            .heightmaps
+            .iter()
+            .any(|(path, modified)| modified_time(path) != *modified);
+    }
+
+    if !changed {
+        return;
+    }
+
+    let graph = match graphs.get(handle) {
+        Some(graph) => graph,
+        None => return,
+    };
+
+    // Taken before compiling, so a change while compiling is picked up next
+    state.heightmaps = graph
+        .heightmap_paths()
+        .into_iter()
+        .map(|path| {
+            let modified = modified_time(&path);
+            (path, modified)
+        })
+        .collect();
+
+    match graph.compile() {
+        Ok(compiled) => *terrain_source = TerrainSource::Graph(compiled),
+        Err(err) => {
+            error!("{}", err);
+            return;
+        }
+    }
+
+    if !authority.local {
+        return;
+    }
+
+    let mut chunks = Vec::new();
+    for (mut chunk, coord) in chunk_query.iter_mut() {
+        let mut regenerated = generate_chunk(&chunk_settings, &terrain_source, coord.origin(&chunk_settings));
+        journal.replay(&chunk_settings, *coord, &mut regenerated);
+        *chunk = regenerated;
+        chunks.push(*coord);
+    }
+
+    rewritten_events.send(ChunksRewritten {
+        chunks,
+        regenerated: true,
+    });
+}
+
+pub struct GenerationPlugin;
+
+impl Plugin for GenerationPlugin {
+    fn build(&self, app: &mut AppBuilder) {
+        app.init_resource::<TerrainSource>()
+            .init_resource::<GeneratorSettings>()
+            .init_resource::<GeneratorGraphState>()
+            .add_asset::<GeneratorGraph>()
+            .init_asset_loader::<GeneratorGraphLoader>()
+            .add_startup_system(load_generator_graph.system())
+            .add_system(apply_generator_graph.system());
+    }
+}
@@ -1,43 +1,32 @@
-use bevy_fly_camera::{FlyCamera, FlyCameraPlugin};
-use bevy_rapier3d::{physics::RapierPhysicsPlugin, rapier::{dynamics::RigidBodyBuilder, geometry::ColliderBuilder, math::Real}};
+use bevy_rapier3d::{physics::RapierPhysicsPlugin, rapier::{dynamics::RigidBodyBuilder, geometry::ColliderBuilder}};
 use camera::third_person_camera::{FollowTarget, ThirdPerson3DCameraBundle, ThirdPersonCamera, ThirdPersonCameraPlugin};
 use settings::SettingsPlugin;
-use crate::chunk::pipeline::MarchMeshMaterial;
-use crate::chunk::pipeline::MARCHING_MESH_MAT;
-use crate::chunk::Chunk;
+use erosion::ErosionPlugin;
+use persistence::PersistencePlugin;
+use network::{NetworkMode, NetworkPlugin};
+use sculpt::SculptPlugin;
+use water::WaterPlugin;
+use generation::{GenerationPlugin, GeneratorSettings, HeightmapFormat, HeightmapImport, TerrainLayer, TerrainSource};
+use std::path::{Path, PathBuf};
 use crate::chunk::MarchingCubesPlugin;
 use bevy_4x_camera::FourXCameraPlugin;
-use chunk::{pipeline::default_marching_mesh_pipeline, ChunkSettings, MarchingChunkBundle};
+use chunk::{raycast::TerrainCursorSource, streaming::ChunkLoader};
 
-use bevy::{
-    prelude::*,
-    render::{
-        pipeline::{PipelineDescriptor, PrimitiveTopology, RenderPipeline},
-        render_graph::{base, AssetRenderResourcesNode, RenderGraph},
-    },
-};
-use bevy_4x_camera::CameraRigBundle;
-use bevy_mod_picking::{
-    DebugPickingPlugin, Group, InteractableMesh, InteractablePickingPlugin, PickSource, PickState,
-    PickingPlugin,
-};
-use triangulation::{edges, triangulation};
+use bevy::prelude::*;
 
-const WIDTH: usize = 60;
-const HEIGHT: usize = 60;
-const LENGTH: usize = 60;
-const thershold: f32 = 0.0f32;
+const HEIGHTMAP_PATH: &str = "assets/heightmaps/terrain.png";
+const GENERATOR_GRAPH_PATH: &str = "terrain/default.ron";
 
 pub mod chunk;
 pub mod triangulation;
 pub mod camera;
 pub mod settings;
-
-struct ModifyLand{
-    incrementing: bool,
-    decrementing: bool,
-    change_value: f32
-}
+pub mod erosion;
+pub mod generation;
+pub mod persistence;
+pub mod sculpt;
+pub mod network;
+pub mod water;
 
 fn main() {
     App::build()
@@ -49,24 +38,25 @@ fn main() {
             vsync: true,
             ..Default::default()
         })
-        .add_resource(ModifyLand {
-            incrementing: false,
-            decrementing: false,
-            change_value: 0.0
-        })
         .add_plugins(DefaultPlugins)
+        // After the default plugins so import errors reach the log
+        .add_resource(terrain_source())
+        .add_resource(generator_settings())
         .add_plugin(FourXCameraPlugin)
-        .add_plugin(PickingPlugin)
-        .add_plugin(InteractablePickingPlugin)
-        .add_plugin(DebugPickingPlugin)
         .add_plugin(MarchingCubesPlugin)
         .add_plugin(RapierPhysicsPlugin)
         .add_plugin(SettingsPlugin)
         .add_plugin(ThirdPersonCameraPlugin)
+        .add_plugin(ErosionPlugin)
+        .add_plugin(GenerationPlugin)
+        .add_plugin(PersistencePlugin)
+        .add_plugin(SculptPlugin)
+        .add_plugin(WaterPlugin)
+        .add_plugin(NetworkPlugin {
+            mode: NetworkMode::from_args(std::env::args().skip(1)),
+        })
         .add_startup_system(setup.system())
-        .add_startup_system(setup_march_mesh.system())
         .add_startup_system(setup_test_object.system())
-        .add_system(select_terrain.system())
         .run();
 }
 
@@ -101,6 +91,7 @@ fn setup(
         .with(RigidBodyBuilder::new_dynamic().translation(10.0, 50.0, 10.0))
         .with(ColliderBuilder::cylinder(1.0, 1.0))
         .with(FollowTarget)
+        .with(ChunkLoader::default())
         .with_children(|parent|{
             parent.spawn(ThirdPerson3DCameraBundle {
                 third_person_camera: ThirdPersonCamera {
@@ -109,71 +100,10 @@ fn setup(
                 },
                 ..Default::default()
             })
-            .with(PickSource::default());
+            .with(TerrainCursorSource);
         });
 }
 
-fn setup_march_mesh(
-    commands: &mut Commands,
-    chunk_settings: Res<ChunkSettings>,
-    mut meshes: ResMut<Assets<Mesh>>,
-    mut materials: ResMut<Assets<MarchMeshMaterial>>,
-    mut pipelines: ResMut<Assets<PipelineDescriptor>>,
-    mut render_graph: ResMut<RenderGraph>,
-    mut shaders: ResMut<Assets<Shader>>,
-) {
-    let pipeline_handle = pipelines.add(default_marching_mesh_pipeline(shaders));
-
-    render_graph.add_system_node(
-        MARCHING_MESH_MAT,
-        AssetRenderResourcesNode::<MarchMeshMaterial>::new(true),
-    );
-
-    render_graph
-        .add_node_edge(MARCHING_MESH_MAT, base::node::MAIN_PASS)
-        .unwrap();
-
-    let mut chunk = Chunk::default();
-    chunk.data = Box::new(vec![
-        vec![
-            vec![0.0; chunk_settings.length];
-            chunk_settings.height
-        ];
-        chunk_settings.width
-    ]);
-
-    for y in 0..chunk_settings.height {
-        for x in 0..chunk_settings.width {
-            for z in 0..chunk_settings.length {
-                chunk.data[x][y][z] = plane(5f32, y as f32);
-            }
-        }
-    }
-
-    let mesh_material_handle = materials.add(MarchMeshMaterial {
-        lightPos: Vec3::new(4.0, 8.0, 4.0),
-        lightColor: Vec3::new(1f32, 1f32, 1f32),
-        objectColor: Vec3::new(0.88, 0.32, 0.39),
-    });
-
-    for z in 0..10 {
-        for x in 0..10 {
-            commands
-                .spawn(MarchingChunkBundle {
-                    mesh: meshes.add(Mesh::new(PrimitiveTopology::TriangleList)),
-                    chunk: chunk.clone(),
-                    render_pipelines: RenderPipelines::from_pipelines(vec![RenderPipeline::new(
-                        pipeline_handle.clone_weak(),
-                    )]),
-                    ..Default::default()
-                })
-                .with(mesh_material_handle.clone_weak())
-                .with(RigidBodyBuilder::new_static().translation(((chunk_settings.width - 1) * x) as Real, 0.0, ((chunk_settings.length - 1) * z) as Real))
-                .with(ColliderBuilder::cuboid(1.0, 1.0, 1.0));
-        }
-    }
-}
-
 fn setup_test_object(
     commands: &mut Commands,
     mut meshes: ResMut<Assets<Mesh>>,
@@ -188,66 +118,39 @@ fn setup_test_object(
     .with(ColliderBuilder::cylinder(1.0, 1.0));
 }
 
-fn inside_sphere(sphere_pos: Vec3, radius: f32, point: Vec3) -> bool {
-    (point.x - sphere_pos.x).powf(2f32)
-        + (point.y - sphere_pos.y).powf(2f32)
-        + (point.z - sphere_pos.z).powf(2f32)
-        < (radius * radius)
+fn generator_settings() -> GeneratorSettings {
+    // An imported heightmap takes precedence over the generator graph
+    GeneratorSettings {
+        graph_path: if Path::new(HEIGHTMAP_PATH).exists() {
+            None
+        } else {
+            Some(GENERATOR_GRAPH_PATH.to_string())
+        },
+    }
 }
 
-fn select_terrain(
-    pick_state: Res<PickState>,
-    chunk_setting: Res<ChunkSettings>,
-    mut corner_query: Query<(&InteractableMesh, &mut Chunk, &Transform, Entity)>,
-) {
-    for (interactable, mut chunk, transform, entity) in &mut corner_query.iter_mut() {
-        let increment_event = interactable
-            .mouse_down_event(&Group::default(), MouseButton::Left)
-            .unwrap();
-
-        let decrement_event = interactable
-            .mouse_down_event(&Group::default(), MouseButton::Right)
-            .unwrap();
-
-        if increment_event.is_none() && decrement_event.is_none() {
-            continue;
-        }
-
-        let (_, intersection) = pick_state.top(Group::default()).unwrap();
-        let sphere_center = intersection.position();
-        let chunk_position = transform.translation;
+fn terrain_source() -> TerrainSource {
+    let import = HeightmapImport {
+        path: PathBuf::from(HEIGHTMAP_PATH),
+        format: HeightmapFormat::Png,
+        horizontal_scale: 1.0,
+        vertical_scale: 30.0,
+        base_height: 2.0,
+    };
 
-        // Gen a sphere and capture chunk data in that sphere
-        for y in 0..chunk_setting.height {
-            for x in 0..chunk_setting.width {
-                for z in 0..chunk_setting.length {
-                    if inside_sphere(
-                        *sphere_center,
-                        3f32,
-                        Vec3::new(
-                            chunk_position.x + x as f32,
-                            chunk_position.y + y as f32,
-                            chunk_position.z + z as f32,
-                        ),
-                    ) {
-                        if !increment_event.is_none() {
-                            chunk.data[x][y][z] += 0.1;
-                        }
+    if !import.path.exists() {
+        return TerrainSource::default();
+    }
 
-                        if !decrement_event.is_none() {
-                            chunk.data[x][y][z] -= 0.1;
-                        }
-                    }
-                }
-            }
+    match import.load() {
+        // The same overhangs the default generator graph has
+        Ok(heightmap) => TerrainSource::Heightmap {
+            heightmap,
+            layers: vec![TerrainLayer::noise(21, 0.08, 2, 1.5)],
+        },
+        Err(err) => {
+            warn!("{}, falling back to a flat plane", err);
+            TerrainSource::default()
         }
     }
 }
-
-fn plane(plane_y: f32, y: f32) -> f32 {
-    return if y > plane_y {
-        thershold + 1f32
-    } else {
-        thershold - 1f32
-    };
-}
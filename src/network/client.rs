@@ -0,0 +1,243 @@
+use std::collections::HashMap;
+use std::io;
+use std::net::TcpStream;
+
+use bevy::prelude::*;
+
+use super::protocol::{ChunkDelta, ClientMessage, Connection, EditRequest, ServerMessage};
+use crate::chunk::{
+    map::{ChunkCoord, ChunkMap},
+    streaming::ChunkLoader,
+    Chunk, ChunkSettings,
+};
+use crate::generation::{generate_chunk, TerrainSource};
+use crate::persistence::{format::decode_chunk, RegionStore};
+use crate::sculpt::TerrainEditEvent;
+
+/// Seconds between position updates sent to the server.
+const POSITION_INTERVAL: f32 = 0.25;
+
+/// Client side of a networked world. Local edits go to the server and the
+/// terrain only changes through the snapshots and deltas it sends back, so
+/// every client ends up with the server's densities.
+pub struct NetworkClient {
+    /// `None` once the server hung up.
+    connection: Option<Connection>,
+    /// Sequence of the last edit applied to each chunk.
+    chunk_sequences: HashMap<ChunkCoord, u64>,
+    since_position: f32,
+}
+
+/// A change from the server the client hasn't applied yet.
+pub enum ChunkUpdate {
+    Snapshot(ChunkCoord, Chunk),
+    Delta(ChunkDelta),
+}
+
+impl NetworkClient {
+    pub fn connect(address: &str) -> io::Result<Self> {
+        let connection = Connection::new(TcpStream::connect(address)?)?;
+
+        Ok(NetworkClient {
+            connection: Some(connection),
+            chunk_sequences: HashMap::new(),
+            since_position: POSITION_INTERVAL,
+        })
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.connection.is_some()
+    }
+
+    /// Queues messages for the server and writes out what the socket takes.
+    pub fn send(&mut self, messages: impl IntoIterator<Item = ClientMessage>) {
+        let connection = match self.connection.as_mut() {
+            Some(connection) => connection,
+            None => return,
+        };
+
+        for message in messages {
+            connection.queue(&message);
+        }
+        if let Err(err) = connection.flush() {
+            warn!("lost connection to the server: {}", err);
+            self.connection = None;
+        }
+    }
+
+    /// Whether the chunk should take a change made as of edit `sequence`,
+    /// marking it seen. A snapshot replaces the whole chunk, so it's taken
+    /// unless a later edit already reached the chunk, while a delta is only
+    /// taken once.
+    fn advance(&mut self, coord: ChunkCoord, sequence: u64, snapshot: bool) -> bool {
+        let applied = self.chunk_sequences.entry(coord).or_insert(0);
+        if sequence < *applied || (sequence == *applied && !snapshot) {
+            return false;
+        }
+
+        *applied = sequence;
+        true
+    }
+
+    /// Every chunk change the server sent since the last call, in order.
+    pub fn receive(&mut self, chunk_settings: &ChunkSettings) -> Vec<ChunkUpdate> {
+        let messages = match self.connection.as_mut().map(|connection| connection.receive::<ServerMessage>()) {
+            Some(Ok(messages)) => messages,
+            Some(Err(err)) => {
+                warn!("lost connection to the server: {}", err);
+                self.connection = None;
+                return Vec::new();
+            }
+            None => return Vec::new(),
+        };
+
+        let mut updates = Vec::new();
+        for message in messages {
+            match message {
+                ServerMessage::Welcome { client_id, sequence } => {
+                    info!("joined the server as client {} at edit {}", client_id, sequence);
+                }
+                ServerMessage::Snapshot { sequence, coord, data } => {
+                    if !self.advance(coord, sequence, true) {
+                        continue;
+                    }
+
+                    match decode_chunk(chunk_settings, &data) {
+                        Ok(decoded) => updates.push(ChunkUpdate::Snapshot(coord, decoded.chunk)),
+                        Err(err) => warn!("bad snapshot of chunk {:?} from the server: {}", coord, err),
+                    }
+                }
+                ServerMessage::Delta { sequence, chunks } => {
+                    for delta in chunks {
+                        if self.advance(delta.coord, sequence, false) {
+                            updates.push(ChunkUpdate::Delta(delta));
+                        }
+                    }
+                }
+                ServerMessage::Rejected { reason } => warn!("server rejected edit: {}", reason),
+            }
+        }
+
+        updates
+    }
+}
+
+pub fn send_edit_requests(
+    time: Res<Time>,
+    edit_events: Res<Events<TerrainEditEvent>>,
+    mut edit_reader: Local<EventReader<TerrainEditEvent>>,
+    mut client: ResMut<NetworkClient>,
+    loader_query: Query<&GlobalTransform, With<ChunkLoader>>,
+) {
+    client.since_position += time.delta_seconds();
+    let send_position = client.since_position >= POSITION_INTERVAL;
+    if send_position {
+        client.since_position = 0.0;
+    }
+
+    let mut messages: Vec<ClientMessage> = edit_reader
+        .iter(&edit_events)
+        .map(|event| {
+            ClientMessage::EditRequest(EditRequest {
+                shape: event.shape,
+                position: [event.position.x, event.position.y, event.position.z],
+                operation: event.operation,
+                strength: event.strength,
+                falloff: event.falloff,
+                material: event.material,
+            })
+        })
+        .collect();
+
+    if send_position {
+        if let Some(transform) = loader_query.iter().next() {
+            let position = transform.translation;
+            messages.push(ClientMessage::Position([position.x, position.y, position.z]));
+        }
+    }
+
+    client.send(messages);
+}
+
+/// Writes the delta's samples into the chunk.
+pub fn write_delta(chunk_settings: &ChunkSettings, chunk: &mut Chunk, delta: &ChunkDelta) {
+    for ([x, y, z], density, material) in delta.samples.iter() {
+        let (x, y, z) = (*x as usize, *y as usize, *z as usize);
+        if x < chunk_settings.width && y < chunk_settings.height && z < chunk_settings.length {
+            chunk.data[x][y][z] = *density;
+            chunk.materials[x][y][z] = *material;
+        }
+    }
+}
+
+/// Writes a chunk received from the server into the world: straight into
+/// the entity if it's loaded, otherwise into the local region store where
+/// streaming picks it up.
+fn store_chunk(
+    chunk_settings: &ChunkSettings,
+    region_store: &RegionStore,
+    chunk_map: &ChunkMap,
+    chunk_query: &mut Query<&mut Chunk>,
+    coord: ChunkCoord,
+    chunk: Chunk,
+) {
+    if let Some(entity) = chunk_map.get(coord) {
+        if let Ok(mut loaded) = chunk_query.get_mut(entity) {
+            *loaded = chunk;
+            return;
+        }
+    }
+
+    if let Err(err) = region_store.save_chunks(chunk_settings, std::iter::once((coord, &chunk))) {
+        error!("failed to store chunk {:?} from the server: {}", coord, err);
+    }
+}
+
+fn apply_delta(
+    chunk_settings: &ChunkSettings,
+    terrain_source: &TerrainSource,
+    region_store: &RegionStore,
+    chunk_map: &ChunkMap,
+    chunk_query: &mut Query<&mut Chunk>,
+    delta: &ChunkDelta,
+) {
+    if let Some(entity) = chunk_map.get(delta.coord) {
+        if let Ok(mut chunk) = chunk_query.get_mut(entity) {
+            write_delta(chunk_settings, &mut *chunk, delta);
+            return;
+        }
+    }
+
+    // Chunks never loaded here start from the generator, same as on the server
+    let mut chunk = match region_store.load_chunk(chunk_settings, delta.coord) {
+        Ok(Some(decoded)) => decoded.chunk,
+        _ => generate_chunk(chunk_settings, terrain_source, delta.coord.origin(chunk_settings)),
+    };
+    write_delta(chunk_settings, &mut chunk, delta);
+    store_chunk(chunk_settings, region_store, chunk_map, chunk_query, delta.coord, chunk);
+}
+
+pub fn receive_server_messages(
+    chunk_settings: Res<ChunkSettings>,
+    terrain_source: Res<TerrainSource>,
+    region_store: Res<RegionStore>,
+    chunk_map: Res<ChunkMap>,
+    mut client: ResMut<NetworkClient>,
+    mut chunk_query: Query<&mut Chunk>,
+) {
+    for update in client.receive(&chunk_settings) {
+        match update {
+            ChunkUpdate::Snapshot(coord, chunk) => {
+                store_chunk(&chunk_settings, &region_store, &chunk_map, &mut chunk_query, coord, chunk)
+            }
+            ChunkUpdate::Delta(delta) => apply_delta(
+                &chunk_settings,
+                &terrain_source,
+                &region_store,
+                &chunk_map,
+                &mut chunk_query,
+                &delta,
+            ),
+        }
+    }
+}
@@ -0,0 +1,98 @@
+use std::fs;
+use std::path::PathBuf;
+
+use bevy::prelude::*;
+
+use crate::persistence::RegionStore;
+use crate::sculpt::TerrainAuthority;
+
+pub mod client;
+pub mod protocol;
+pub mod server;
+
+pub use client::NetworkClient;
+pub use server::{NetworkServer, ServerSettings};
+
+/// Where a client keeps the chunks the server sent. It's cleared on every
+/// start since the server's snapshots are the only source of truth.
+const CLIENT_CACHE_DIRECTORY: &str = "saves/client";
+
+#[derive(Clone, Debug)]
+pub enum NetworkMode {
+    Offline,
+    /// Hosts the world and applies every edit.
+    Server { address: String },
+    /// Joins a server and only shows what it sends.
+    Client { address: String },
+}
+
+impl Default for NetworkMode {
+    fn default() -> Self {
+        NetworkMode::Offline
+    }
+}
+
+impl NetworkMode {
+    /// `--server <address>` hosts, `--connect <address>` joins.
+    pub fn from_args(mut args: impl Iterator<Item = String>) -> Self {
+        let mut mode = NetworkMode::Offline;
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--server" => {
+                    if let Some(address) = args.next() {
+                        mode = NetworkMode::Server { address };
+                    }
+                }
+                "--connect" => {
+                    if let Some(address) = args.next() {
+                        mode = NetworkMode::Client { address };
+                    }
+                }
+                _ => {}
+            }
+        }
+        mode
+    }
+}
+
+pub struct NetworkPlugin {
+    pub mode: NetworkMode,
+}
+
+impl Plugin for NetworkPlugin {
+    fn build(&self, app: &mut AppBuilder) {
+        match &self.mode {
+            NetworkMode::Offline => {}
+            NetworkMode::Server { address } => match NetworkServer::bind(address) {
+                Ok(server) => {
+                    info!("listening for clients on {}", address);
+                    app.add_resource(server)
+                        .init_resource::<ServerSettings>()
+                        .add_system(server::accept_clients.system())
+                        .add_system(server::send_snapshots.system())
+                        .add_system(server::receive_client_messages.system())
+                        .add_system_to_stage(stage::POST_UPDATE, server::broadcast_terrain_edits.system())
+                        .add_system_to_stage(stage::POST_UPDATE, server::broadcast_rewritten_chunks.system());
+                }
+                Err(err) => error!("failed to listen on {}, running offline: {}", address, err),
+            },
+            NetworkMode::Client { address } => match NetworkClient::connect(address) {
+                Ok(client) => {
+                    if let Err(err) = fs::remove_dir_all(CLIENT_CACHE_DIRECTORY) {
+                        if err.kind() != std::io::ErrorKind::NotFound {
+                            warn!("failed to clear the client cache: {}", err);
+                        }
+                    }
+
+                    info!("connected to {}", address);
+                    app.add_resource(client)
+                        .add_resource(TerrainAuthority { local: false })
+                        .add_resource(RegionStore::new(PathBuf::from(CLIENT_CACHE_DIRECTORY)))
+                        .add_system(client::send_edit_requests.system())
+                        .add_system_to_stage(stage::PRE_UPDATE, client::receive_server_messages.system());
+                }
+                Err(err) => error!("failed to connect to {}, running offline: {}", address, err),
+            },
+        }
+    }
+}
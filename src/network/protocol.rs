@@ -0,0 +1,128 @@
+use std::convert::TryInto;
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::chunk::map::ChunkCoord;
+use crate::sculpt::{BrushShape, EditMode, Falloff};
+
+/// Largest frame either side accepts, a snapshot is far below this.
+const MAX_FRAME_SIZE: usize = 4 * 1024 * 1024;
+
+/// A `TerrainEditEvent` as sent by a client.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct EditRequest {
+    pub shape: BrushShape,
+    pub position: [f32; 3],
+    pub operation: EditMode,
+    pub strength: f32,
+    pub falloff: Falloff,
+    pub material: Option<u8>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum ClientMessage {
+    EditRequest(EditRequest),
+    /// Where the client's player is, so the server keeps the chunks around
+    /// it loaded.
+    Position([f32; 3]),
+}
+
+/// New values of the samples an edit changed in one chunk.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ChunkDelta {
+    pub coord: ChunkCoord,
+    pub samples: Vec<([u16; 3], f32, u8)>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum ServerMessage {
+    Welcome { client_id: u32, sequence: u64 },
+    /// A whole chunk in the save format, as of edit `sequence`.
+    Snapshot { sequence: u64, coord: ChunkCoord, data: Vec<u8> },
+    /// The result of edit `sequence`. Sample values are absolute, so
+    /// applying a delta twice is harmless.
+    Delta { sequence: u64, chunks: Vec<ChunkDelta> },
+    Rejected { reason: String },
+}
+
+/// A nonblocking TCP stream carrying length prefixed bincode frames.
+pub struct Connection {
+    stream: TcpStream,
+    incoming: Vec<u8>,
+    outgoing: Vec<u8>,
+}
+
+impl Connection {
+    pub fn new(stream: TcpStream) -> io::Result<Self> {
+        stream.set_nonblocking(true)?;
+        stream.set_nodelay(true)?;
+
+        Ok(Connection {
+            stream,
+            incoming: Vec::new(),
+            outgoing: Vec::new(),
+        })
+    }
+
+    /// Queues a message, `flush` sends it.
+    pub fn queue<M: Serialize>(&mut self, message: &M) {
+        let bytes = bincode::serialize(message).expect("network messages always serialize");
+        self.outgoing.extend_from_slice(&(bytes.len() as u32).to_le_bytes());
+        self.outgoing.extend_from_slice(&bytes);
+    }
+
+    /// Bytes queued but not written yet.
+    pub fn outgoing_len(&self) -> usize {
+        self.outgoing.len()
+    }
+
+    /// Writes as much of the queue as the socket takes without blocking.
+    pub fn flush(&mut self) -> io::Result<()> {
+        while !self.outgoing.is_empty() {
+            match self.stream.write(&self.outgoing) {
+                Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+                Ok(written) => {
+                    self.outgoing.drain(..written);
+                }
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => break,
+                Err(err) => return Err(err),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Every complete message received so far. Errors once the peer hung up
+    /// or sent something unreadable.
+    pub fn receive<M: DeserializeOwned>(&mut self) -> io::Result<Vec<M>> {
+        let mut buffer = [0u8; 16 * 1024];
+        loop {
+            match self.stream.read(&mut buffer) {
+                Ok(0) => return Err(io::ErrorKind::ConnectionAborted.into()),
+                Ok(read) => self.incoming.extend_from_slice(&buffer[..read]),
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => break,
+                Err(err) => return Err(err),
+            }
+        }
+
+        let mut messages = Vec::new();
+        while self.incoming.len() >= 4 {
+            let length = u32::from_le_bytes(self.incoming[0..4].try_into().unwrap()) as usize;
+            if length > MAX_FRAME_SIZE {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "network frame is too large"));
+            }
+            if self.incoming.len() < 4 + length {
+                break;
+            }
+
+            let message = bincode::deserialize(&self.incoming[4..4 + length])
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err.to_string()))?;
+            messages.push(message);
+            self.incoming.drain(..4 + length);
+        }
+
+        Ok(messages)
+    }
+}
@@ -0,0 +1,753 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::io;
+use std::net::{SocketAddr, TcpListener};
+
+use bevy::prelude::*;
+
+use super::protocol::{ChunkDelta, ClientMessage, Connection, EditRequest, ServerMessage};
+use crate::chunk::{
+    map::{ChunkCoord, ChunkMap},
+    streaming::ChunkLoader,
+    terrain::voxel_locations,
+    Chunk, ChunkSettings, ChunksRewritten,
+};
+use crate::generation::{generate_chunk, TerrainSource};
+use crate::persistence::{format::encode_chunk, EditJournal, RegionStore};
+use crate::sculpt::{brush::MATERIAL_COUNT, EditMode, SampleChange, TerrainDetached, TerrainEditEvent, TerrainEdited};
+
+pub struct ServerSettings {
+    /// Largest brush radius a client may use.
+    pub max_radius: f32,
+    /// Deltas kept for clients that join later.
+    pub recent_log_size: usize,
+    /// Edit requests a client may send per second, a little above the
+    /// brush rate. Requests beyond it are rejected.
+    pub max_edits_per_second: f32,
+    /// Snapshots sent to each client per frame, so a join doesn't encode
+    /// the whole world in one frame.
+    pub snapshots_per_frame: usize,
+    /// Bytes a client may leave unread before it's dropped.
+    pub max_outgoing_bytes: usize,
+}
+
+impl Default for ServerSettings {
+    fn default() -> Self {
+        Self {
+            max_radius: 16.0,
+            recent_log_size: 256,
+            max_edits_per_second: 15.0,
+            snapshots_per_frame: 8,
+            max_outgoing_bytes: 16 * 1024 * 1024,
+        }
+    }
+}
+
+struct RemoteClient {
+    id: u32,
+    connection: Connection,
+    /// Edit requests the client may still send, refilled at
+    /// `max_edits_per_second` up to one second's worth.
+    edit_allowance: f32,
+    /// Chunks still to send in full, encoded as they're sent so they hold
+    /// every edit applied until then.
+    pending_snapshots: VecDeque<ChunkCoord>,
+    /// Keeps the chunks around the client's player loaded on the server.
+    loader: Entity,
+}
+
+/// What clients sent since the last frame.
+#[derive(Default)]
+struct Received {
+    edits: Vec<EditRequest>,
+    positions: Vec<(Entity, [f32; 3])>,
+    /// Loaders of the clients that left.
+    left: Vec<Entity>,
+}
+
+/// Authoritative side of a networked world. Clients send edit requests, the
+/// server applies them like local edits and broadcasts the changed samples
+/// in the order it applied them.
+pub struct NetworkServer {
+    listener: TcpListener,
+    clients: Vec<RemoteClient>,
+    next_client_id: u32,
+    sequence: u64,
+    recent: VecDeque<ServerMessage>,
+    /// Chunks changed outside of edits, by undo, erosion or regenerating
+    /// them. Clients joining later need snapshots of them too.
+    rewritten: HashSet<ChunkCoord>,
+}
+
+impl NetworkServer {
+    pub fn bind(address: &str) -> io::Result<Self> {
+        let listener = TcpListener::bind(address)?;
+        listener.set_nonblocking(true)?;
+
+        Ok(NetworkServer {
+            listener,
+            clients: Vec::new(),
+            next_client_id: 1,
+            sequence: 0,
+            recent: VecDeque::new(),
+            rewritten: HashSet::new(),
+        })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// Takes every waiting connection, welcomes it with the recent deltas
+    /// and queues snapshots of the edited `chunks` and the rewritten ones.
+    fn accept(&mut self, chunks: impl Fn() -> HashSet<ChunkCoord>, mut spawn_loader: impl FnMut() -> Entity) {
+        loop {
+            let (stream, address) = match self.listener.accept() {
+                Ok(accepted) => accepted,
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => break,
+                Err(err) => {
+                    warn!("failed to accept client: {}", err);
+                    break;
+                }
+            };
+
+            let mut connection = match Connection::new(stream) {
+                Ok(connection) => connection,
+                Err(err) => {
+                    warn!("failed to set up connection to {}: {}", address, err);
+                    continue;
+                }
+            };
+
+            let id = self.next_client_id;
+            self.next_client_id += 1;
+
+            connection.queue(&ServerMessage::Welcome {
+                client_id: id,
+                sequence: self.sequence,
+            });
+            for delta in self.recent.iter() {
+                connection.queue(delta);
+            }
+
+            let mut snapshots = chunks();
+            snapshots.extend(self.rewritten.iter().copied());
+
+            info!("client {} joined from {}", id, address);
+            self.clients.push(RemoteClient {
+                id,
+                connection,
+                edit_allowance: 0.0,
+                pending_snapshots: snapshots.into_iter().collect(),
+                loader: spawn_loader(),
+            });
+        }
+    }
+
+    /// Queues snapshots of chunks that changed without an edit for every
+    /// client.
+    fn rewrite(&mut self, chunks: &[ChunkCoord]) {
+        self.rewritten.extend(chunks.iter().copied());
+        for client in self.clients.iter_mut() {
+            for coord in chunks.iter() {
+                if !client.pending_snapshots.contains(coord) {
+                    client.pending_snapshots.push_back(*coord);
+                }
+            }
+        }
+    }
+
+    /// Sends each client its next few pending snapshots, skipping clients
+    /// that haven't read what they were sent yet.
+    fn send_snapshots(&mut self, settings: &ServerSettings, mut encode: impl FnMut(ChunkCoord) -> Vec<u8>) {
+        let sequence = self.sequence;
+        for client in self.clients.iter_mut() {
+            if client.connection.outgoing_len() > settings.max_outgoing_bytes / 2 {
+                continue;
+            }
+
+            for _ in 0..settings.snapshots_per_frame {
+                let coord = match client.pending_snapshots.pop_front() {
+                    Some(coord) => coord,
+                    None => break,
+                };
+                client.connection.queue(&ServerMessage::Snapshot {
+                    sequence,
+                    coord,
+                    data: encode(coord),
+                });
+            }
+        }
+    }
+
+    /// Reads every client, refilling their edit allowance for `delta_seconds`
+    /// and rejecting the edits `validate` refuses.
+    fn receive(
+        &mut self,
+        settings: &ServerSettings,
+        delta_seconds: f32,
+        validate: impl Fn(&EditRequest) -> Result<(), String>,
+    ) -> Received {
+        let mut received = Received::default();
+
+        for client in self.clients.iter_mut() {
+            client.edit_allowance = (client.edit_allowance + delta_seconds * settings.max_edits_per_second)
+                .min(settings.max_edits_per_second);
+
+            let messages = match client.connection.receive::<ClientMessage>() {
+                Ok(messages) => messages,
+                Err(err) => {
+                    info!("client {} disconnected: {}", client.id, err);
+                    received.left.push(client.loader);
+                    continue;
+                }
+            };
+
+            for message in messages {
+                match message {
+                    ClientMessage::EditRequest(request) => {
+                        if client.edit_allowance < 1.0 {
+                            client.connection.queue(&ServerMessage::Rejected {
+                                reason: "too many edits".to_string(),
+                            });
+                            continue;
+                        }
+                        client.edit_allowance -= 1.0;
+
+                        if let Err(reason) = validate(&request) {
+                            client.connection.queue(&ServerMessage::Rejected { reason });
+                            continue;
+                        }
+
+                        received.edits.push(request);
+                    }
+                    ClientMessage::Position(position) => {
+                        if position.iter().all(|value| value.is_finite()) {
+                            received.positions.push((client.loader, position));
+                        }
+                    }
+                }
+            }
+        }
+
+        let left = &received.left;
+        self.clients.retain(|client| !left.contains(&client.loader));
+        received
+    }
+
+    /// Numbers the changes and sends the samples to all clients.
+    fn broadcast_changes(
+        &mut self,
+        settings: &ServerSettings,
+        chunk_settings: &ChunkSettings,
+        changed_chunks: &[ChunkCoord],
+        changes: &[SampleChange],
+    ) {
+        let mut chunks: HashMap<ChunkCoord, Vec<([u16; 3], f32, u8)>> = HashMap::new();
+        for change in changes.iter() {
+            for (coord, [x, y, z]) in voxel_locations(chunk_settings, change.voxel) {
+                if changed_chunks.contains(&coord) {
+                    chunks.entry(coord).or_insert_with(Vec::new).push((
+                        [x as u16, y as u16, z as u16],
+                        change.after,
+                        change.material_after,
+                    ));
+                }
+            }
+        }
+
+        self.sequence += 1;
+        let delta = ServerMessage::Delta {
+            sequence: self.sequence,
+            chunks: chunks
+                .into_iter()
+                .map(|(coord, samples)| ChunkDelta { coord, samples })
+                .collect(),
+        };
+
+        for client in self.clients.iter_mut() {
+            client.connection.queue(&delta);
+        }
+
+        self.recent.push_back(delta);
+        while self.recent.len() > settings.recent_log_size {
+            self.recent.pop_front();
+        }
+    }
+
+    /// Writes out what every client has queued. Clients that hung up or fell
+    /// more than `max_outgoing_bytes` behind are dropped, returning their
+    /// loaders.
+    fn flush(&mut self, settings: &ServerSettings) -> Vec<Entity> {
+        let mut left = Vec::new();
+        for client in self.clients.iter_mut() {
+            if let Err(err) = client.connection.flush() {
+                info!("client {} disconnected: {}", client.id, err);
+                left.push(client.loader);
+            } else if client.connection.outgoing_len() > settings.max_outgoing_bytes {
+                warn!("dropping client {}, it fell too far behind", client.id);
+                left.push(client.loader);
+            }
+        }
+
+        self.clients.retain(|client| !left.contains(&client.loader));
+        left
+    }
+}
+
+/// The current state of a chunk, wherever it lives.
+fn current_chunk(
+    chunk_settings: &ChunkSettings,
+    terrain_source: &TerrainSource,
+    region_store: &RegionStore,
+    journal: &EditJournal,
+    chunk_map: &ChunkMap,
+    chunk_query: &Query<&Chunk>,
+    coord: ChunkCoord,
+) -> Chunk {
+    if let Some(chunk) = chunk_map.get(coord).and_then(|entity| chunk_query.get(entity).ok()) {
+        return chunk.clone();
+    }
+
+    match region_store.load_chunk(chunk_settings, coord) {
+        Ok(Some(decoded)) => decoded.chunk,
+        _ => {
+            let mut chunk = generate_chunk(chunk_settings, terrain_source, coord.origin(chunk_settings));
+            journal.replay(chunk_settings, coord, &mut chunk);
+            chunk
+        }
+    }
+}
+
+/// Welcomes new clients with the recent deltas and queues a snapshot of
+/// every changed chunk for them. Besides the edited ones that includes the
+/// saved chunks, which hold what erosion changed in earlier sessions.
+pub fn accept_clients(
+    commands: &mut Commands,
+    chunk_settings: Res<ChunkSettings>,
+    region_store: Res<RegionStore>,
+    journal: Res<EditJournal>,
+    mut server: ResMut<NetworkServer>,
+) {
+    server.accept(
+        || {
+            let mut chunks = journal.edited_chunks(&chunk_settings);
+            match region_store.saved_chunks() {
+                Ok(saved) => chunks.extend(saved),
+                Err(err) => warn!("failed to list the saved chunks: {}", err),
+            }
+            chunks
+        },
+        || {
+            commands
+                .spawn((ChunkLoader::default(), Transform::default(), GlobalTransform::default()))
+                .current_entity()
+                .unwrap()
+        },
+    );
+}
+
+/// Sends clients snapshots of the chunks undo, erosion or regenerating
+/// rewrote, since those changes aren't edits with deltas.
+pub fn broadcast_rewritten_chunks(
+    rewritten_events: Res<Events<ChunksRewritten>>,
+    mut rewritten_reader: Local<EventReader<ChunksRewritten>>,
+    mut server: ResMut<NetworkServer>,
+) {
+    for rewritten in rewritten_reader.iter(&rewritten_events) {
+        server.rewrite(&rewritten.chunks);
+    }
+}
+
+/// Sends the snapshots clients are waiting for, a few per frame.
+#[allow(clippy::too_many_arguments)] // Bevy systems take their resources as parameters
+pub fn send_snapshots(
+    settings: Res<ServerSettings>,
+    chunk_settings: Res<ChunkSettings>,
+    terrain_source: Res<TerrainSource>,
+    region_store: Res<RegionStore>,
+    journal: Res<EditJournal>,
+    chunk_map: Res<ChunkMap>,
+    mut server: ResMut<NetworkServer>,
+    chunk_query: Query<&Chunk>,
+) {
+    server.send_snapshots(&settings, |coord| {
+        let chunk = current_chunk(
+            &chunk_settings,
+            &terrain_source,
+            &region_store,
+            &journal,
+            &chunk_map,
+            &chunk_query,
+            coord,
+        );
+        encode_chunk(&chunk_settings, &chunk)
+    });
+}
+
+fn validate(
+    settings: &ServerSettings,
+    chunk_settings: &ChunkSettings,
+    chunk_map: &ChunkMap,
+    request: &EditRequest,
+) -> Result<(), String> {
+    if !request.position.iter().all(|value| value.is_finite()) || !request.strength.is_finite() {
+        return Err("edit has a non finite value".to_string());
+    }
+    if request.strength < 0.0 || request.strength > 1.0 {
+        return Err("edit strength must be between 0 and 1".to_string());
+    }
+
+    // Every extent counts, a cylinder or cone can be far taller than it's wide
+    let (min, max) = request.shape.bounds();
+    let half_extents = (max - min) * 0.5;
+    for &extent in [half_extents.x, half_extents.y, half_extents.z].iter() {
+        if !(extent > 0.0 && extent <= settings.max_radius) {
+            return Err(format!("brush extents must be between 0 and {}", settings.max_radius));
+        }
+    }
+
+    match request.operation {
+        EditMode::Flatten { normal } => {
+            let length_squared: f32 = normal.iter().map(|value| value * value).sum();
+            if !(length_squared.is_finite() && length_squared > 0.0) {
+                return Err("flatten normal must be finite and non zero".to_string());
+            }
+        }
+        EditMode::Noise { frequency } => {
+            if !(frequency.is_finite() && frequency != 0.0) {
+                return Err("noise frequency must be finite and non zero".to_string());
+            }
+        }
+        _ => (),
+    }
+
+    let material = match request.operation {
+        EditMode::Paint { material } => Some(material),
+        _ => request.material,
+    };
+    if material.map_or(false, |material| material >= MATERIAL_COUNT) {
+        return Err("unknown material".to_string());
+    }
+
+    let position = Vec3::new(request.position[0], request.position[1], request.position[2]);
+    if chunk_map.chunks_in_aabb(chunk_settings, position + min, position + max).is_empty() {
+        return Err("terrain there isn't loaded".to_string());
+    }
+
+    Ok(())
+}
+
+#[allow(clippy::too_many_arguments)] // Bevy systems take their resources as parameters
+pub fn receive_client_messages(
+    commands: &mut Commands,
+    time: Res<Time>,
+    settings: Res<ServerSettings>,
+    chunk_settings: Res<ChunkSettings>,
+    chunk_map: Res<ChunkMap>,
+    mut server: ResMut<NetworkServer>,
+    mut edit_events: ResMut<Events<TerrainEditEvent>>,
+    mut loader_query: Query<&mut Transform, With<ChunkLoader>>,
+) {
+    let received = server.receive(&settings, time.delta_seconds(), |request| {
+        validate(&settings, &chunk_settings, &chunk_map, request)
+    });
+
+    for request in received.edits {
+        edit_events.send(TerrainEditEvent {
+            shape: request.shape,
+            position: Vec3::new(request.position[0], request.position[1], request.position[2]),
+            operation: request.operation,
+            strength: request.strength,
+            falloff: request.falloff,
+            material: request.material,
+            stroke: None,
+        });
+    }
+    for (loader, position) in received.positions {
+        if let Ok(mut transform) = loader_query.get_mut(loader) {
+            transform.translation = Vec3::new(position[0], position[1], position[2]);
+        }
+    }
+    for loader in received.left {
+        commands.despawn(loader);
+    }
+}
+
+/// Numbers every applied edit and detached island and sends the samples they
+/// changed to all clients.
+#[allow(clippy::too_many_arguments)] // Bevy systems take their resources as parameters
+pub fn broadcast_terrain_edits(
+    commands: &mut Commands,
+    settings: Res<ServerSettings>,
+    chunk_settings: Res<ChunkSettings>,
+    edited_events: Res<Events<TerrainEdited>>,
+    mut edited_reader: Local<EventReader<TerrainEdited>>,
+    detached_events: Res<Events<TerrainDetached>>,
+    mut detached_reader: Local<EventReader<TerrainDetached>>,
+    mut server: ResMut<NetworkServer>,
+) {
+    for edited in edited_reader.iter(&edited_events) {
+        server.broadcast_changes(&settings, &chunk_settings, &edited.chunks, &edited.changes);
+    }
+    for detached in detached_reader.iter(&detached_events) {
+        server.broadcast_changes(&settings, &chunk_settings, &detached.chunks, &detached.changes);
+    }
+
+    for loader in server.flush(&settings) {
+        commands.despawn(loader);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+    use std::time::Duration;
+
+    use super::*;
+    use crate::chunk::terrain::TerrainView;
+    use crate::chunk::test_util::settings;
+    use crate::network::client::{write_delta, ChunkUpdate, NetworkClient};
+    use crate::sculpt::{edit::EDIT_VERSION, BrushShape, Falloff, TerrainEdit};
+
+    fn ground(chunk_settings: &ChunkSettings) -> Chunk {
+        let mut chunk = Chunk::new(chunk_settings);
+        for plane in chunk.data.iter_mut() {
+            for (y, row) in plane.iter_mut().enumerate() {
+                for density in row.iter_mut() {
+                    *density = y as f32 - 8.0;
+                }
+            }
+        }
+        chunk
+    }
+
+    fn request(position: [f32; 3]) -> EditRequest {
+        EditRequest {
+            shape: BrushShape::Sphere { radius: 3.0 },
+            position,
+            operation: EditMode::Raise,
+            strength: 1.0,
+            falloff: Falloff::Constant,
+            material: None,
+        }
+    }
+
+    /// A client with the chunks it was sent, starting from the same ground as
+    /// the server.
+    struct TestClient {
+        client: NetworkClient,
+        chunks: HashMap<ChunkCoord, Chunk>,
+        snapshots: usize,
+        deltas: usize,
+    }
+
+    impl TestClient {
+        fn connect(server: &NetworkServer) -> Self {
+            TestClient {
+                client: NetworkClient::connect(&server.local_addr().unwrap().to_string()).unwrap(),
+                chunks: HashMap::new(),
+                snapshots: 0,
+                deltas: 0,
+            }
+        }
+
+        fn receive(&mut self, chunk_settings: &ChunkSettings) {
+            for update in self.client.receive(chunk_settings) {
+                match update {
+                    ChunkUpdate::Snapshot(coord, chunk) => {
+                        self.snapshots += 1;
+                        self.chunks.insert(coord, chunk);
+                    }
+                    ChunkUpdate::Delta(delta) => {
+                        self.deltas += 1;
+                        let chunk = self.chunks.entry(delta.coord).or_insert_with(|| ground(chunk_settings));
+                        write_delta(chunk_settings, chunk, &delta);
+                    }
+                }
+            }
+        }
+
+        fn matches(&self, coord: ChunkCoord, chunk: &Chunk) -> bool {
+            self.chunks
+                .get(&coord)
+                .map_or(false, |own| own.data == chunk.data && own.materials == chunk.materials)
+        }
+    }
+
+    fn wait_until(mut done: impl FnMut() -> bool) {
+        for _ in 0..400 {
+            if done() {
+                return;
+            }
+            thread::sleep(Duration::from_millis(5));
+        }
+        panic!("timed out waiting for the network");
+    }
+
+    /// Waits for one edit request, applies it to the server's chunk and
+    /// broadcasts the result.
+    fn apply_next_edit(
+        server: &mut NetworkServer,
+        settings: &ServerSettings,
+        chunk_settings: &ChunkSettings,
+        coord: ChunkCoord,
+        chunk: &mut Chunk,
+    ) {
+        let mut requests = Vec::new();
+        wait_until(|| {
+            requests.extend(server.receive(settings, 1.0, |_| Ok(())).edits);
+            !requests.is_empty()
+        });
+
+        for request in requests {
+            let edit = TerrainEdit {
+                version: EDIT_VERSION,
+                stroke: 0,
+                shape: request.shape,
+                falloff: request.falloff,
+                position: request.position,
+                strength: request.strength,
+                mode: request.operation,
+                material: request.material,
+                timestamp: 0.0,
+            };
+            let changes = edit.apply(&mut TerrainView::detached(chunk_settings, coord, &mut *chunk));
+            server.broadcast_changes(settings, chunk_settings, &[coord], &changes);
+        }
+        assert!(server.flush(settings).is_empty());
+    }
+
+    #[test]
+    fn oversized_and_degenerate_edits_are_rejected() {
+        let chunk_settings = settings();
+        let settings = ServerSettings::default();
+        let mut chunk_map = ChunkMap::default();
+        chunk_map.insert(ChunkCoord::new(0, 0, 0), Entity::new(1));
+        let check = |request: EditRequest| validate(&settings, &chunk_settings, &chunk_map, &request);
+
+        assert!(check(request([6.0, 8.0, 6.0])).is_ok());
+
+        let shapes = [
+            BrushShape::Cylinder {
+                radius: 2.0,
+                half_height: 1.0e6,
+            },
+            BrushShape::Cone {
+                radius: 2.0,
+                height: -4.0,
+            },
+            BrushShape::Cone {
+                radius: 2.0,
+                height: f32::INFINITY,
+            },
+        ];
+        for &shape in shapes.iter() {
+            assert!(check(EditRequest {
+                shape,
+                ..request([6.0, 8.0, 6.0])
+            })
+            .is_err());
+        }
+
+        let operations = [
+            EditMode::Flatten { normal: [0.0; 3] },
+            EditMode::Flatten {
+                normal: [f32::NAN, 1.0, 0.0],
+            },
+            EditMode::Noise { frequency: 0.0 },
+            EditMode::Noise {
+                frequency: f32::INFINITY,
+            },
+        ];
+        for &operation in operations.iter() {
+            assert!(check(EditRequest {
+                operation,
+                ..request([6.0, 8.0, 6.0])
+            })
+            .is_err());
+        }
+    }
+
+    #[test]
+    fn clients_end_up_with_the_servers_chunks() {
+        let chunk_settings = settings();
+        let settings = ServerSettings::default();
+        let coord = ChunkCoord::new(0, 0, 0);
+        let mut chunk = ground(&chunk_settings);
+        let mut edited: Vec<ChunkCoord> = Vec::new();
+
+        let mut server = NetworkServer::bind("127.0.0.1:0").unwrap();
+        let mut first = TestClient::connect(&server);
+        wait_until(|| {
+            server.accept(|| edited.iter().copied().collect(), || Entity::new(1));
+            server.clients.len() == 1
+        });
+
+        first.client.send(vec![ClientMessage::EditRequest(request([6.0, 8.0, 6.0]))]);
+        apply_next_edit(&mut server, &settings, &chunk_settings, coord, &mut chunk);
+        edited.push(coord);
+        wait_until(|| {
+            first.receive(&chunk_settings);
+            first.matches(coord, &chunk)
+        });
+
+        // A late joiner gets the recent deltas and a snapshot of the edited chunk
+        let mut second = TestClient::connect(&server);
+        wait_until(|| {
+            server.accept(|| edited.iter().copied().collect(), || Entity::new(2));
+            server.clients.len() == 2
+        });
+        server.send_snapshots(&settings, |_| encode_chunk(&chunk_settings, &chunk));
+        assert!(server.flush(&settings).is_empty());
+        wait_until(|| {
+            second.receive(&chunk_settings);
+            second.snapshots == 1 && second.deltas == 1
+        });
+        assert!(second.matches(coord, &chunk));
+
+        second.client.send(vec![ClientMessage::EditRequest(request([10.0, 8.0, 9.0]))]);
+        apply_next_edit(&mut server, &settings, &chunk_settings, coord, &mut chunk);
+        wait_until(|| {
+            first.receive(&chunk_settings);
+            second.receive(&chunk_settings);
+            first.matches(coord, &chunk) && second.matches(coord, &chunk)
+        });
+        assert!(first.client.is_connected() && second.client.is_connected());
+    }
+
+    #[test]
+    fn clients_falling_behind_are_dropped() {
+        let chunk_settings = settings();
+        let settings = ServerSettings {
+            max_outgoing_bytes: 1024,
+            ..Default::default()
+        };
+        let mut server = NetworkServer::bind("127.0.0.1:0").unwrap();
+        let _client = TestClient::connect(&server);
+        wait_until(|| {
+            server.accept(HashSet::new, || Entity::new(1));
+            server.clients.len() == 1
+        });
+
+        // The client never reads, so the socket buffers fill up
+        let changes: Vec<SampleChange> = (0..16 * 16 * 16)
+            .map(|index| SampleChange {
+                voxel: [index % 16, index / 16 % 16, index / 256],
+                before: 1.0,
+                after: -1.0,
+                material_before: 0,
+                material_after: 0,
+            })
+            .collect();
+        let mut dropped = Vec::new();
+        wait_until(|| {
+            for _ in 0..8 {
+                server.broadcast_changes(&settings, &chunk_settings, &[ChunkCoord::new(0, 0, 0)], &changes);
+            }
+            dropped.extend(server.flush(&settings));
+            !dropped.is_empty()
+        });
+        assert_eq!(dropped, vec![Entity::new(1)]);
+        assert!(server.clients.is_empty());
+    }
+}
@@ -0,0 +1,301 @@
+use std::convert::TryInto;
+use std::fmt;
+
+use crate::chunk::{Chunk, ChunkSettings};
+
+const CHUNK_MAGIC: &[u8; 4] = b"MCCK";
+const HEADER_SIZE: usize = 20;
+
+/// Version written by `encode_chunk`. Bump it whenever the payload layout
+/// changes and keep a decoder for every older version.
+pub const FORMAT_VERSION: u16 = 2;
+
+#[derive(Debug)]
+pub enum ChunkFormatError {
+    Truncated,
+    UnsupportedVersion(u16),
+    SettingsMismatch {
+        width: usize,
+        height: usize,
+        length: usize,
+    },
+    ChecksumMismatch,
+    Decompress(String),
+    WrongSampleCount,
+}
+
+impl fmt::Display for ChunkFormatError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ChunkFormatError::Truncated => write!(f, "chunk data is truncated"),
+            ChunkFormatError::UnsupportedVersion(version) => {
+                write!(f, "chunk format version {} is newer than this build supports", version)
+            }
+            ChunkFormatError::SettingsMismatch { width, height, length } => write!(
+                f,
+                "chunk was saved as {}x{}x{} which doesn't match the current chunk settings",
+                width, height, length
+            ),
+            ChunkFormatError::ChecksumMismatch => write!(f, "chunk checksum doesn't match, the data is corrupted"),
+            ChunkFormatError::Decompress(err) => write!(f, "failed to decompress chunk: {}", err),
+            ChunkFormatError::WrongSampleCount => write!(f, "chunk has the wrong number of samples"),
+        }
+    }
+}
+
+impl std::error::Error for ChunkFormatError {}
+
+pub struct DecodedChunk {
+    pub chunk: Chunk,
+    /// The chunk was stored in an older format and should be written back.
+    pub migrated: bool,
+}
+
+/// Header (magic, format version, chunk dimensions, payload checksum)
+/// followed by the LZ4 compressed payload. Each density is stored as its bits
+/// XORed with the previous sample's, which turns the long runs of similar
+/// values in a chunk into runs of zero bytes. The material of every sample
+/// follows the densities, one byte each.
+pub fn encode_chunk(chunk_settings: &ChunkSettings, chunk: &Chunk) -> Vec<u8> {
+    let sample_count = chunk_settings.width * chunk_settings.height * chunk_settings.length;
+    let mut samples = Vec::with_capacity(sample_count * 5);
+
+    let mut previous = 0u32;
+    for x in 0..chunk_settings.width {
+        for y in 0..chunk_settings.height {
+            for z in 0..chunk_settings.length {
+                let bits = chunk.data[x][y][z].to_bits();
+                samples.extend_from_slice(&(bits ^ previous).to_le_bytes());
+                previous = bits;
+            }
+        }
+    }
+
+    for x in 0..chunk_settings.width {
+        for y in 0..chunk_settings.height {
+            samples.extend_from_slice(&chunk.materials[x][y]);
+        }
+    }
+
+    let payload = lz4_flex::compress_prepend_size(&samples);
+
+    let mut bytes = Vec::with_capacity(HEADER_SIZE + payload.len());
+    bytes.extend_from_slice(CHUNK_MAGIC);
+    bytes.extend_from_slice(&FORMAT_VERSION.to_le_bytes());
+    bytes.extend_from_slice(&(chunk_settings.width as u16).to_le_bytes());
+    bytes.extend_from_slice(&(chunk_settings.height as u16).to_le_bytes());
+    bytes.extend_from_slice(&(chunk_settings.length as u16).to_le_bytes());
+    bytes.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    bytes.extend_from_slice(&crc32fast::hash(&payload).to_le_bytes());
+    bytes.extend_from_slice(&payload);
+
+    bytes
+}
+
+pub fn decode_chunk(chunk_settings: &ChunkSettings, bytes: &[u8]) -> Result<DecodedChunk, ChunkFormatError> {
+    if bytes.len() < 4 || &bytes[0..4] != CHUNK_MAGIC {
+        // Saves from before the format was versioned start straight with the
+        // dimensions
+        return decode_unversioned(chunk_settings, bytes).map(|chunk| DecodedChunk { chunk, migrated: true });
+    }
+
+    if bytes.len() < HEADER_SIZE {
+        return Err(ChunkFormatError::Truncated);
+    }
+
+    let version = u16::from_le_bytes(bytes[4..6].try_into().unwrap());
+    let width = u16::from_le_bytes(bytes[6..8].try_into().unwrap()) as usize;
+    let height = u16::from_le_bytes(bytes[8..10].try_into().unwrap()) as usize;
+    let length = u16::from_le_bytes(bytes[10..12].try_into().unwrap()) as usize;
+    let payload_length = u32::from_le_bytes(bytes[12..16].try_into().unwrap()) as usize;
+    let checksum = u32::from_le_bytes(bytes[16..20].try_into().unwrap());
+
+    check_settings(chunk_settings, width, height, length)?;
+
+    let payload = bytes
+        .get(HEADER_SIZE..HEADER_SIZE + payload_length)
+        .ok_or(ChunkFormatError::Truncated)?;
+
+    if crc32fast::hash(payload) != checksum {
+        return Err(ChunkFormatError::ChecksumMismatch);
+    }
+
+    match version {
+        1 => decode_v1(chunk_settings, payload).map(|chunk| DecodedChunk { chunk, migrated: true }),
+        2 => decode_v2(chunk_settings, payload).map(|chunk| DecodedChunk { chunk, migrated: false }),
+        _ => Err(ChunkFormatError::UnsupportedVersion(version)),
+    }
+}
+
+fn check_settings(
+    chunk_settings: &ChunkSettings,
+    width: usize,
+    height: usize,
+    length: usize,
+) -> Result<(), ChunkFormatError> {
+    if width != chunk_settings.width || height != chunk_settings.height || length != chunk_settings.length {
+        return Err(ChunkFormatError::SettingsMismatch { width, height, length });
+    }
+
+    Ok(())
+}
+
+/// Densities only, every sample gets the default material.
+fn decode_v1(chunk_settings: &ChunkSettings, payload: &[u8]) -> Result<Chunk, ChunkFormatError> {
+    let samples = lz4_flex::decompress_size_prepended(payload)
+        .map_err(|err| ChunkFormatError::Decompress(err.to_string()))?;
+
+    if samples.len() != chunk_settings.width * chunk_settings.height * chunk_settings.length * 4 {
+        return Err(ChunkFormatError::WrongSampleCount);
+    }
+
+    let mut chunk = Chunk::new(chunk_settings);
+    read_densities(chunk_settings, &samples, &mut chunk);
+
+    Ok(chunk)
+}
+
+fn decode_v2(chunk_settings: &ChunkSettings, payload: &[u8]) -> Result<Chunk, ChunkFormatError> {
+    let samples = lz4_flex::decompress_size_prepended(payload)
+        .map_err(|err| ChunkFormatError::Decompress(err.to_string()))?;
+
+    let sample_count = chunk_settings.width * chunk_settings.height * chunk_settings.length;
+    if samples.len() != sample_count * 5 {
+        return Err(ChunkFormatError::WrongSampleCount);
+    }
+
+    let mut chunk = Chunk::new(chunk_settings);
+    read_densities(chunk_settings, &samples[..sample_count * 4], &mut chunk);
+
+    let mut materials = samples[sample_count * 4..].chunks_exact(chunk_settings.length);
+    for x in 0..chunk_settings.width {
+        for y in 0..chunk_settings.height {
+            chunk.materials[x][y].copy_from_slice(materials.next().unwrap());
+        }
+    }
+
+    Ok(chunk)
+}
+
+/// Undoes the XOR delta encoding of the densities.
+fn read_densities(chunk_settings: &ChunkSettings, samples: &[u8], chunk: &mut Chunk) {
+    let mut samples = samples.chunks_exact(4);
+    let mut previous = 0u32;
+    for x in 0..chunk_settings.width {
+        for y in 0..chunk_settings.height {
+            for z in 0..chunk_settings.length {
+                let bits = u32::from_le_bytes(samples.next().unwrap().try_into().unwrap()) ^ previous;
+                chunk.data[x][y][z] = f32::from_bits(bits);
+                previous = bits;
+            }
+        }
+    }
+}
+
+/// Uncompressed layout without a header: dimensions as three `u32`s and
+/// then every density as an `f32`.
+fn decode_unversioned(chunk_settings: &ChunkSettings, bytes: &[u8]) -> Result<Chunk, ChunkFormatError> {
+    if bytes.len() < 12 {
+        return Err(ChunkFormatError::Truncated);
+    }
+
+    let width = u32::from_le_bytes(bytes[0..4].try_into().unwrap()) as usize;
+    let height = u32::from_le_bytes(bytes[4..8].try_into().unwrap()) as usize;
+    let length = u32::from_le_bytes(bytes[8..12].try_into().unwrap()) as usize;
+
+    check_settings(chunk_settings, width, height, length)?;
+
+    if bytes.len() != 12 + width * height * length * 4 {
+        return Err(ChunkFormatError::WrongSampleCount);
+    }
+
+    let mut chunk = Chunk::new(chunk_settings);
+    let mut samples = bytes[12..].chunks_exact(4);
+    for x in 0..width {
+        for y in 0..height {
+            for z in 0..length {
+                chunk.data[x][y][z] = f32::from_le_bytes(samples.next().unwrap().try_into().unwrap());
+            }
+        }
+    }
+
+    Ok(chunk)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settings() -> ChunkSettings {
+        ChunkSettings {
+            width: 4,
+            height: 5,
+            length: 6,
+            threshold: 0.0,
+        }
+    }
+
+    fn sample_chunk(chunk_settings: &ChunkSettings) -> Chunk {
+        let mut chunk = Chunk::new(chunk_settings);
+        for x in 0..chunk_settings.width {
+            for y in 0..chunk_settings.height {
+                for z in 0..chunk_settings.length {
+                    chunk.data[x][y][z] = y as f32 - 2.5 + x as f32 * 0.1;
+                    chunk.materials[x][y][z] = (x + z) as u8;
+                }
+            }
+        }
+        chunk
+    }
+
+    #[test]
+    fn encoded_chunk_decodes_unchanged() {
+        let chunk_settings = settings();
+        let chunk = sample_chunk(&chunk_settings);
+
+        let decoded = decode_chunk(&chunk_settings, &encode_chunk(&chunk_settings, &chunk)).unwrap();
+        assert!(!decoded.migrated);
+        assert_eq!(decoded.chunk.data, chunk.data);
+        assert_eq!(decoded.chunk.materials, chunk.materials);
+    }
+
+    #[test]
+    fn corrupted_payload_fails_the_checksum() {
+        let chunk_settings = settings();
+        let mut bytes = encode_chunk(&chunk_settings, &sample_chunk(&chunk_settings));
+        let last = bytes.len() - 1;
+        bytes[last] ^= 0xFF;
+
+        assert!(matches!(
+            decode_chunk(&chunk_settings, &bytes),
+            Err(ChunkFormatError::ChecksumMismatch)
+        ));
+    }
+
+    #[test]
+    fn other_chunk_size_is_rejected() {
+        let chunk_settings = settings();
+        let bytes = encode_chunk(&chunk_settings, &sample_chunk(&chunk_settings));
+        let other = ChunkSettings {
+            width: 16,
+            ..chunk_settings
+        };
+
+        assert!(matches!(
+            decode_chunk(&other, &bytes),
+            Err(ChunkFormatError::SettingsMismatch { width: 4, .. })
+        ));
+    }
+
+    #[test]
+    fn newer_version_is_rejected() {
+        let chunk_settings = settings();
+        let mut bytes = encode_chunk(&chunk_settings, &sample_chunk(&chunk_settings));
+        bytes[4..6].copy_from_slice(&(FORMAT_VERSION + 1).to_le_bytes());
+
+        assert!(matches!(
+            decode_chunk(&chunk_settings, &bytes),
+            Err(ChunkFormatError::UnsupportedVersion(_))
+        ));
+    }
+}
@@ -0,0 +1,372 @@
+use std::collections::HashSet;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::path::PathBuf;
+
+use bevy::log::{error, warn};
+use bevy::math::Vec3;
+
+use crate::chunk::{
+    map::ChunkCoord,
+    terrain::{voxel_locations, TerrainView},
+    Chunk, ChunkSettings,
+};
+use crate::sculpt::{SampleChange, TerrainEdit};
+use serde::{Deserialize, Serialize};
+
+/// Final density and material of one voxel.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub struct RecordedSample {
+    pub voxel: [i32; 3],
+    pub density: f32,
+    pub material: u8,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum JournalEntry {
+    Edit(TerrainEdit),
+    /// The stroke was undone, its edits are skipped on replay. Only older
+    /// journals hold these, undo and redo are journaled as the samples they
+    /// restore now.
+    Undo(u64),
+    Redo(u64),
+    /// Samples written outright, for changes a replayed chunk can't
+    /// reproduce on its own.
+    Samples { stroke: u64, samples: Vec<RecordedSample> },
+}
+
+/// Append-only log of every terrain edit, one RON entry per line. Replaying
+/// it over freshly generated chunks reproduces the edited world.
+pub struct EditJournal {
+    pub path: PathBuf,
+    pub entries: Vec<JournalEntry>,
+    next_stroke: u64,
+    /// Strokes whose last `Undo` entry wasn't followed by a `Redo`, from
+    /// journals written before undo was journaled as samples.
+    undone: HashSet<u64>,
+    /// Opened on the first append, flushed by `flush` once a frame.
+    writer: Option<BufWriter<File>>,
+}
+
+impl Default for EditJournal {
+    fn default() -> Self {
+        Self {
+            path: PathBuf::from("saves/world/edits.journal"),
+            entries: Vec::new(),
+            next_stroke: 1,
+            undone: HashSet::new(),
+            writer: None,
+        }
+    }
+}
+
+impl EditJournal {
+    /// Reads the journal from disk, skipping any line that fails to parse so
+    /// one damaged entry doesn't lose the rest of the history.
+    pub fn load(&mut self) -> io::Result<()> {
+        let contents = match fs::read_to_string(&self.path) {
+            Ok(contents) => contents,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(err) => return Err(err),
+        };
+
+        self.entries.clear();
+        self.undone.clear();
+        for (line_number, line) in contents.lines().enumerate() {
+            if line.trim().is_empty() {
+                continue;
+            }
+
+            // Journals written before undo support hold bare edits
+            let entry = ron::de::from_str::<JournalEntry>(line)
+                .or_else(|err| ron::de::from_str::<TerrainEdit>(line).map(JournalEntry::Edit).map_err(|_| err));
+
+            match entry {
+                Ok(entry) => {
+                    self.track_undo(&entry);
+                    self.entries.push(entry);
+                }
+                Err(err) => warn!("skipping journal line {}: {}", line_number + 1, err),
+            }
+        }
+
+        self.next_stroke = self
+            .entries
+            .iter()
+            .filter_map(|entry| match entry {
+                JournalEntry::Edit(edit) => Some(edit.stroke),
+                JournalEntry::Samples { stroke, .. } => Some(*stroke),
+                _ => None,
+            })
+            .max()
+            .unwrap_or(0)
+            + 1;
+
+        Ok(())
+    }
+
+    /// Stroke ids stay unique across sessions so undo entries in the journal
+    /// never refer to the wrong stroke.
+    pub fn next_stroke_id(&mut self) -> u64 {
+        let id = self.next_stroke;
+        self.next_stroke += 1;
+        id
+    }
+
+    pub fn record(&mut self, edit: TerrainEdit) {
+        self.push(JournalEntry::Edit(edit));
+    }
+
+    /// Records the result of changes rather than the edit causing them.
+    pub fn record_samples<'c>(&mut self, stroke: u64, changes: impl IntoIterator<Item = &'c SampleChange>) {
+        let samples = changes
+            .into_iter()
+            .map(|change| RecordedSample {
+                voxel: change.voxel,
+                density: change.after,
+                material: change.material_after,
+            })
+            .collect();
+        self.push(JournalEntry::Samples { stroke, samples });
+    }
+
+    /// Records the values undoing a stroke wrote back. Other edits may have
+    /// landed on the same samples since, e.g. from network clients, so
+    /// skipping the stroke on replay wouldn't give the same densities.
+    pub fn record_undo<'c>(&mut self, stroke: u64, changes: impl IntoIterator<Item = &'c SampleChange>) {
+        let samples = changes
+            .into_iter()
+            .map(|change| RecordedSample {
+                voxel: change.voxel,
+                density: change.before,
+                material: change.material_before,
+            })
+            .collect();
+        self.push(JournalEntry::Samples { stroke, samples });
+    }
+
+    /// Records the values redoing a stroke wrote back.
+    pub fn record_redo<'c>(&mut self, stroke: u64, changes: impl IntoIterator<Item = &'c SampleChange>) {
+        self.record_samples(stroke, changes);
+    }
+
+    fn push(&mut self, entry: JournalEntry) {
+        if let Err(err) = self.append(&entry) {
+            error!("failed to write edit journal: {}", err);
+        }
+        self.track_undo(&entry);
+        self.entries.push(entry);
+    }
+
+    fn track_undo(&mut self, entry: &JournalEntry) {
+        match entry {
+            JournalEntry::Undo(stroke) => {
+                self.undone.insert(*stroke);
+            }
+            JournalEntry::Redo(stroke) => {
+                self.undone.remove(stroke);
+            }
+            _ => {}
+        }
+    }
+
+    fn append(&mut self, entry: &JournalEntry) -> io::Result<()> {
+        let line = ron::ser::to_string(entry).map_err(|err| io::Error::new(io::ErrorKind::Other, err.to_string()))?;
+
+        if self.writer.is_none() {
+            if let Some(directory) = self.path.parent() {
+                fs::create_dir_all(directory)?;
+            }
+            let file = OpenOptions::new().create(true).append(true).open(&self.path)?;
+            self.writer = Some(BufWriter::new(file));
+        }
+
+        writeln!(self.writer.as_mut().unwrap(), "{}", line)
+    }
+
+    /// Writes the buffered entries out to the file.
+    pub fn flush(&mut self) -> io::Result<()> {
+        match self.writer.as_mut() {
+            Some(writer) => writer.flush(),
+            None => Ok(()),
+        }
+    }
+
+    /// Every chunk a recorded edit or sample may have changed.
+    pub fn edited_chunks(&self, chunk_settings: &ChunkSettings) -> HashSet<ChunkCoord> {
+        let steps = Vec3::new(
+            (chunk_settings.width - 1) as f32,
+            (chunk_settings.height - 1) as f32,
+            (chunk_settings.length - 1) as f32,
+        );
+
+        let mut chunks = HashSet::new();
+        for entry in self.entries.iter() {
+            if let JournalEntry::Samples { samples, .. } = entry {
+                for sample in samples.iter() {
+                    chunks.extend(voxel_locations(chunk_settings, sample.voxel).map(|(coord, _)| coord));
+                }
+            }
+
+            if let JournalEntry::Edit(edit) = entry {
+                let (min, max) = edit.bounds();
+                let first = ChunkCoord::from_world(chunk_settings, min - steps);
+                let last = ChunkCoord::from_world(chunk_settings, max);
+                for x in first.x..=last.x {
+                    for y in first.y..=last.y {
+                        for z in first.z..=last.z {
+                            let coord = ChunkCoord::new(x, y, z);
+                            if edit.overlaps(chunk_settings, coord) {
+                                chunks.insert(coord);
+                            }
+                        }
+                    }
+                }
+            }
+        }
+
+        chunks
+    }
+
+    /// Applies, in order, every recorded edit and sample overlapping the
+    /// chunk. Edits are replayed on the chunk alone, which is why edits
+    /// reading their neighbours are journaled as samples instead.
+    pub fn replay(&self, chunk_settings: &ChunkSettings, coord: ChunkCoord, chunk: &mut Chunk) {
+        let mut terrain = TerrainView::detached(chunk_settings, coord, chunk);
+
+        for entry in self.entries.iter() {
+            match entry {
+                JournalEntry::Edit(edit) => {
+                    if edit.overlaps(chunk_settings, coord) && !self.undone.contains(&edit.stroke) {
+                        edit.apply(&mut terrain);
+                    }
+                }
+                JournalEntry::Samples { stroke, samples } => {
+                    if !self.undone.contains(stroke) {
+                        for sample in samples.iter() {
+                            terrain.set_voxel(sample.voxel, sample.density);
+                            terrain.set_material(sample.voxel, sample.material);
+                        }
+                    }
+                }
+                JournalEntry::Undo(_) | JournalEntry::Redo(_) => {}
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chunk::test_util::{air, settings};
+    use crate::sculpt::{BrushShape, EditMode, Falloff};
+
+    fn journal(name: &str) -> EditJournal {
+        let path = std::env::temp_dir().join(format!("journal-test-{}-{}.journal", name, std::process::id()));
+        let _ = fs::remove_file(&path);
+        EditJournal {
+            path,
+            ..Default::default()
+        }
+    }
+
+    fn raise(stroke: u64) -> TerrainEdit {
+        TerrainEdit {
+            version: crate::sculpt::edit::EDIT_VERSION,
+            stroke,
+            shape: BrushShape::Sphere { radius: 2.0 },
+            falloff: Falloff::Constant,
+            position: [8.0, 8.0, 8.0],
+            strength: 1.0,
+            mode: EditMode::Raise,
+            material: None,
+            timestamp: 0.0,
+        }
+    }
+
+    #[test]
+    fn undo_and_redo_replay_the_samples_they_restored() {
+        let chunk_settings = settings();
+        let coord = ChunkCoord::new(0, 0, 0);
+        let mut journal = journal("undo");
+
+        let mut live = air(&chunk_settings);
+        let changes = raise(1).apply(&mut TerrainView::detached(&chunk_settings, coord, &mut live));
+        journal.record(raise(1));
+        journal.record_undo(1, changes.iter());
+
+        let mut chunk = air(&chunk_settings);
+        journal.replay(&chunk_settings, coord, &mut chunk);
+        assert_eq!(chunk.data[8][8][8], 1.0);
+
+        journal.record_redo(1, changes.iter());
+        let mut chunk = air(&chunk_settings);
+        journal.replay(&chunk_settings, coord, &mut chunk);
+        assert_eq!(chunk.data, live.data);
+
+        fs::remove_file(&journal.path).unwrap();
+    }
+
+    #[test]
+    fn old_undo_entries_skip_their_stroke() {
+        let chunk_settings = settings();
+        let mut journal = journal("legacy-undo");
+        journal.record(raise(1));
+        journal.push(JournalEntry::Undo(1));
+
+        let mut chunk = air(&chunk_settings);
+        journal.replay(&chunk_settings, ChunkCoord::new(0, 0, 0), &mut chunk);
+        assert_eq!(chunk.data[8][8][8], 1.0);
+
+        journal.push(JournalEntry::Redo(1));
+        journal.replay(&chunk_settings, ChunkCoord::new(0, 0, 0), &mut chunk);
+        assert!(chunk.data[8][8][8] <= 0.0);
+
+        fs::remove_file(&journal.path).unwrap();
+    }
+
+    #[test]
+    fn samples_replay_into_every_chunk_holding_them() {
+        let chunk_settings = settings();
+        let mut journal = journal("samples");
+        let change = SampleChange {
+            voxel: [15, 3, 3],
+            before: 1.0,
+            after: -0.5,
+            material_before: 0,
+            material_after: 2,
+        };
+        journal.record_samples(4, std::iter::once(&change));
+
+        let chunks = journal.edited_chunks(&chunk_settings);
+        assert!(chunks.contains(&ChunkCoord::new(0, 0, 0)));
+        assert!(chunks.contains(&ChunkCoord::new(1, 0, 0)));
+
+        let mut right = air(&chunk_settings);
+        journal.replay(&chunk_settings, ChunkCoord::new(1, 0, 0), &mut right);
+        assert_eq!(right.data[0][3][3], -0.5);
+        assert_eq!(right.materials[0][3][3], 2);
+
+        fs::remove_file(&journal.path).unwrap();
+    }
+
+    #[test]
+    fn journal_loads_back_with_its_undo_state() {
+        let mut journal = journal("load");
+        journal.record(raise(3));
+        journal.record(raise(5));
+        journal.push(JournalEntry::Undo(5));
+        journal.flush().unwrap();
+
+        let mut loaded = EditJournal {
+            path: journal.path.clone(),
+            ..Default::default()
+        };
+        loaded.load().unwrap();
+        assert_eq!(loaded.entries.len(), 3);
+        assert!(loaded.undone.contains(&5));
+        assert_eq!(loaded.next_stroke_id(), 6);
+
+        fs::remove_file(&journal.path).unwrap();
+    }
+}
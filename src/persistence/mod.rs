@@ -0,0 +1,169 @@
+use std::collections::HashSet;
+
+use bevy::{app::AppExit, prelude::*};
+
+use crate::chunk::{
+    map::{ChunkCoord, ChunkMap},
+    Chunk, ChunkSettings, ChunksRewritten,
+};
+use crate::generation::{generate_chunk, TerrainSource};
+use crate::sculpt::TerrainAuthority;
+
+pub mod format;
+pub mod journal;
+pub mod region;
+
+pub use journal::EditJournal;
+pub use region::RegionStore;
+
+/// Chunks changed since they were last written to disk.
+#[derive(Default)]
+pub struct PendingSaves {
+    pub chunks: HashSet<ChunkCoord>,
+}
+
+pub struct SaveSettings {
+    /// Seconds between automatic saves of the modified chunks.
+    pub autosave_interval: f32,
+}
+
+impl Default for SaveSettings {
+    fn default() -> Self {
+        Self {
+            autosave_interval: 30.0,
+        }
+    }
+}
+
+fn load_edit_journal(region_store: Res<RegionStore>, mut journal: ResMut<EditJournal>) {
+    journal.path = region_store.directory.join("edits.journal");
+    if let Err(err) = journal.load() {
+        error!("failed to read edit journal: {}", err);
+    }
+}
+
+fn flush_edit_journal(mut journal: ResMut<EditJournal>) {
+    if let Err(err) = journal.flush() {
+        error!("failed to write edit journal: {}", err);
+    }
+}
+
+/// Regenerates every loaded chunk from the generator and replays the journal
+/// over it, to check the journal reproduces what's on screen. Network clients
+/// have no journal of their own, so they can't.
+fn replay_edit_journal(
+    authority: Res<TerrainAuthority>,
+    keyboard_input: Res<Input<KeyCode>>,
+    chunk_settings: Res<ChunkSettings>,
+    terrain_source: Res<TerrainSource>,
+    journal: Res<EditJournal>,
+    mut rewritten_events: ResMut<Events<ChunksRewritten>>,
+    mut chunk_query: Query<(&mut Chunk, &ChunkCoord)>,
+) {
+    if !authority.local || !keyboard_input.just_pressed(KeyCode::F9) {
+        return;
+    }
+
+    let mut chunks = Vec::new();
+    for (mut chunk, coord) in chunk_query.iter_mut() {
+        let mut replayed = generate_chunk(&chunk_settings, &terrain_source, coord.origin(&chunk_settings));
+        journal.replay(&chunk_settings, *coord, &mut replayed);
+        *chunk = replayed;
+        chunks.push(*coord);
+    }
+
+    rewritten_events.send(ChunksRewritten {
+        chunks,
+        regenerated: true,
+    });
+}
+
+/// Queues every changed chunk for saving, except ones that were only
+/// regenerated and can be rebuilt from the generator and journal. Their saved
+/// copies are dropped, otherwise they'd come back when the chunk reloads.
+fn track_modified_chunks(
+    region_store: Res<RegionStore>,
+    mut pending_saves: ResMut<PendingSaves>,
+    rewritten_events: Res<Events<ChunksRewritten>>,
+    mut rewritten_reader: Local<EventReader<ChunksRewritten>>,
+    chunk_query: Query<&ChunkCoord, Mutated<Chunk>>,
+) {
+    let regenerated: HashSet<ChunkCoord> = rewritten_reader
+        .iter(&rewritten_events)
+        .filter(|rewritten| rewritten.regenerated)
+        .flat_map(|rewritten| rewritten.chunks.iter().copied())
+        .collect();
+
+    for coord in chunk_query.iter() {
+        if !regenerated.contains(coord) {
+            pending_saves.chunks.insert(*coord);
+        }
+    }
+
+    if regenerated.is_empty() {
+        return;
+    }
+    for coord in regenerated.iter() {
+        pending_saves.chunks.remove(coord);
+    }
+    if let Err(err) = region_store.remove_chunks(regenerated.into_iter()) {
+        error!("failed to drop the saved copies of regenerated chunks: {}", err);
+    }
+}
+
+#[allow(clippy::too_many_arguments)] // Bevy systems take their resources as parameters
+fn save_modified_chunks(
+    time: Res<Time>,
+    keyboard_input: Res<Input<KeyCode>>,
+    save_settings: Res<SaveSettings>,
+    app_exit_events: Res<Events<AppExit>>,
+    mut app_exit_reader: Local<EventReader<AppExit>>,
+    mut since_last_save: Local<f32>,
+    region_store: Res<RegionStore>,
+    chunk_settings: Res<ChunkSettings>,
+    chunk_map: Res<ChunkMap>,
+    mut pending_saves: ResMut<PendingSaves>,
+    chunk_query: Query<&Chunk>,
+) {
+    *since_last_save += time.delta_seconds();
+
+    let exiting = app_exit_reader.iter(&app_exit_events).next().is_some();
+    if !exiting && !keyboard_input.just_pressed(KeyCode::F5) && *since_last_save < save_settings.autosave_interval {
+        return;
+    }
+    *since_last_save = 0.0;
+
+    if pending_saves.chunks.is_empty() {
+        return;
+    }
+
+    let chunks: Vec<(ChunkCoord, &Chunk)> = pending_saves
+        .chunks
+        .iter()
+        .filter_map(|coord| {
+            let entity = chunk_map.get(*coord)?;
+            chunk_query.get(entity).ok().map(|chunk| (*coord, chunk))
+        })
+        .collect();
+
+    match region_store.save_chunks(&chunk_settings, chunks.into_iter()) {
+        Ok(()) => pending_saves.chunks.clear(),
+        Err(err) => error!("failed to save chunks: {}", err),
+    }
+}
+
+pub struct PersistencePlugin;
+
+impl Plugin for PersistencePlugin {
+    fn build(&self, app: &mut AppBuilder) {
+        app.init_resource::<RegionStore>()
+            .init_resource::<PendingSaves>()
+            .init_resource::<SaveSettings>()
+            .init_resource::<EditJournal>()
+            .add_startup_system(load_edit_journal.system())
+            .add_system(replay_edit_journal.system())
+            .add_system_to_stage(stage::POST_UPDATE, track_modified_chunks.system())
+            .add_system_to_stage(stage::LAST, save_modified_chunks.system())
+            .add_system_to_stage(stage::LAST, flush_edit_journal.system());
+    }
+}
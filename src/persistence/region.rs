@@ -0,0 +1,480 @@
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use bevy::prelude::*;
+
+use super::format::{decode_chunk, encode_chunk, DecodedChunk};
+use crate::chunk::{map::ChunkCoord, Chunk, ChunkSettings};
+
+const REGION_MAGIC: &[u8; 4] = b"MCRG";
+const REGION_HEADER_SIZE: u64 = 8;
+
+/// Offset and length of every chunk slot in a region file, offset 0 for an
+/// empty slot.
+type OffsetTable = Vec<(u32, u32)>;
+
+/// Chunks on disk, grouped into cubic regions of `region_size` chunks per
+/// side. A region file is a header, a table with the offset and length of
+/// every chunk slot and then the chunk blobs.
+///
+/// Saving a chunk appends its blob and then points its slot at it, so a
+/// crash mid-save leaves the slot on the previous blob. The space of
+/// replaced blobs is reclaimed by rewriting the region once it's mostly
+/// dead.
+pub struct RegionStore {
+    pub directory: PathBuf,
+    pub region_size: i32,
+    /// Offset tables of the regions touched so far, so loading or saving a
+    /// chunk only reads and writes its own slot.
+    tables: Mutex<HashMap<ChunkCoord, OffsetTable>>,
+}
+
+impl Default for RegionStore {
+    fn default() -> Self {
+        RegionStore::new(PathBuf::from("saves/world"))
+    }
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+impl RegionStore {
+    pub fn new(directory: PathBuf) -> Self {
+        RegionStore {
+            directory,
+            region_size: 8,
+            tables: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn slot_count(&self) -> usize {
+        (self.region_size * self.region_size * self.region_size) as usize
+    }
+
+    fn data_start(&self) -> u64 {
+        REGION_HEADER_SIZE + self.slot_count() as u64 * 8
+    }
+
+    /// The region holding the chunk and the chunk's slot inside it.
+    fn locate(&self, coord: ChunkCoord) -> (ChunkCoord, usize) {
+        let size = self.region_size;
+        let region = ChunkCoord::new(
+            coord.x.div_euclid(size),
+            coord.y.div_euclid(size),
+            coord.z.div_euclid(size),
+        );
+        let slot = (coord.x.rem_euclid(size) * size * size + coord.y.rem_euclid(size) * size + coord.z.rem_euclid(size))
+            as usize;
+
+        (region, slot)
+    }
+
+    fn region_path(&self, region: ChunkCoord) -> PathBuf {
+        self.directory
+            .join(format!("r.{}.{}.{}.region", region.x, region.y, region.z))
+    }
+
+    /// Reads the header and offset table of a region file, an empty table if
+    /// the region was never saved.
+    fn read_table(&self, region: ChunkCoord) -> io::Result<OffsetTable> {
+        let mut file = match File::open(self.region_path(region)) {
+            Ok(file) => file,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(vec![(0, 0); self.slot_count()]),
+            Err(err) => return Err(err),
+        };
+
+        let file_length = file.metadata()?.len();
+        let mut header = vec![0; self.data_start() as usize];
+        if file_length < self.data_start() || file.read_exact(&mut header).is_err() || &header[0..4] != REGION_MAGIC {
+            return Err(invalid_data("region header is corrupted"));
+        }
+
+        if u32::from_le_bytes(header[4..8].try_into().unwrap()) != self.region_size as u32 {
+            return Err(invalid_data("region was saved with a different region size"));
+        }
+
+        let mut table = Vec::with_capacity(self.slot_count());
+        for entry in header[REGION_HEADER_SIZE as usize..].chunks_exact(8) {
+            let offset = u32::from_le_bytes(entry[0..4].try_into().unwrap());
+            let length = u32::from_le_bytes(entry[4..8].try_into().unwrap());
+
+            if offset != 0 && offset as u64 + length as u64 > file_length {
+                return Err(invalid_data("region offset table points past the end of the file"));
+            }
+            table.push((offset, length));
+        }
+
+        Ok(table)
+    }
+
+    /// Runs `f` with the region's cached offset table, reading it first if
+    /// this is the first time the region is touched.
+    fn with_table<T>(&self, region: ChunkCoord, f: impl FnOnce(&mut OffsetTable) -> io::Result<T>) -> io::Result<T> {
+        let mut tables = self.tables.lock().unwrap();
+        if !tables.contains_key(&region) {
+            let table = self.read_table(region)?;
+            tables.insert(region, table);
+        }
+
+        f(tables.get_mut(&region).unwrap())
+    }
+
+    /// Writes a fresh region file holding only the given blobs, next to the
+    /// region first and then swapped in so a crash can't leave half of it.
+    fn write_region(&self, region: ChunkCoord, blobs: &[Option<Vec<u8>>]) -> io::Result<OffsetTable> {
+        fs::create_dir_all(&self.directory)?;
+
+        let mut table = Vec::with_capacity(blobs.len());
+        let mut data = Vec::new();
+        let data_start = self.data_start() as usize;
+
+        for blob in blobs.iter() {
+            match blob {
+                Some(blob) => {
+                    table.push(((data_start + data.len()) as u32, blob.len() as u32));
+                    data.extend_from_slice(blob);
+                }
+                None => table.push((0, 0)),
+            }
+        }
+
+        let mut bytes = Vec::with_capacity(data_start + data.len());
+        bytes.extend_from_slice(REGION_MAGIC);
+        bytes.extend_from_slice(&(self.region_size as u32).to_le_bytes());
+        for (offset, length) in table.iter() {
+            bytes.extend_from_slice(&offset.to_le_bytes());
+            bytes.extend_from_slice(&length.to_le_bytes());
+        }
+        bytes.extend_from_slice(&data);
+
+        let path = self.region_path(region);
+        let temp_path = path.with_extension("region.tmp");
+        fs::write(&temp_path, bytes)?;
+        fs::rename(temp_path, path)?;
+
+        Ok(table)
+    }
+
+    fn read_blob(file: &mut File, offset: u32, length: u32) -> io::Result<Vec<u8>> {
+        let mut blob = vec![0; length as usize];
+        file.seek(SeekFrom::Start(offset as u64))?;
+        file.read_exact(&mut blob)?;
+        Ok(blob)
+    }
+
+    pub fn load_chunk(&self, chunk_settings: &ChunkSettings, coord: ChunkCoord) -> io::Result<Option<DecodedChunk>> {
+        let (region, slot) = self.locate(coord);
+        let (offset, length) = self.with_table(region, |table| Ok(table[slot]))?;
+        if offset == 0 {
+            return Ok(None);
+        }
+
+        let mut file = File::open(self.region_path(region))?;
+        let blob = Self::read_blob(&mut file, offset, length)?;
+        decode_chunk(chunk_settings, &blob)
+            .map(Some)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+    }
+
+    /// Every chunk with a slot in one of the region files.
+    pub fn saved_chunks(&self) -> io::Result<Vec<ChunkCoord>> {
+        let entries = match fs::read_dir(&self.directory) {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(err) => return Err(err),
+        };
+
+        let size = self.region_size;
+        let mut chunks = Vec::new();
+        for entry in entries {
+            let path = entry?.path();
+            if path.extension().map_or(true, |extension| extension != "region") {
+                continue;
+            }
+
+            // Region files are named r.<x>.<y>.<z>.region
+            let name = path.file_stem().and_then(|stem| stem.to_str()).unwrap_or("");
+            let parts: Vec<i32> = name.split('.').skip(1).filter_map(|part| part.parse().ok()).collect();
+            if !name.starts_with("r.") || parts.len() != 3 {
+                continue;
+            }
+            let region = ChunkCoord::new(parts[0], parts[1], parts[2]);
+
+            let table = self.with_table(region, |table| Ok(table.clone()))?;
+            for (slot, (offset, _)) in table.iter().enumerate() {
+                if *offset == 0 {
+                    continue;
+                }
+
+                let slot = slot as i32;
+                chunks.push(ChunkCoord::new(
+                    region.x * size + slot / (size * size),
+                    region.y * size + slot / size % size,
+                    region.z * size + slot % size,
+                ));
+            }
+        }
+
+        Ok(chunks)
+    }
+
+    pub fn save_chunks<'a>(
+        &self,
+        chunk_settings: &ChunkSettings,
+        chunks: impl Iterator<Item = (ChunkCoord, &'a Chunk)>,
+    ) -> io::Result<()> {
+        let mut regions: HashMap<ChunkCoord, Vec<(usize, &Chunk)>> = HashMap::new();
+        for (coord, chunk) in chunks {
+            let (region, slot) = self.locate(coord);
+            regions.entry(region).or_insert_with(Vec::new).push((slot, chunk));
+        }
+
+        for (region, chunks) in regions {
+            let blobs: Vec<(usize, Vec<u8>)> = chunks
+                .into_iter()
+                .map(|(slot, chunk)| (slot, encode_chunk(chunk_settings, chunk)))
+                .collect();
+
+            match self.with_table(region, |table| self.write_slots(region, table, &blobs)) {
+                Err(err) if err.kind() == io::ErrorKind::InvalidData => {
+                    // Keep the unreadable region around for recovery and
+                    // start a new one instead of writing over it
+                    let path = self.region_path(region);
+                    let bad_path = path.with_extension("region.bad");
+                    warn!("region {:?} is unreadable ({}), moving it to {:?}", region, err, bad_path);
+                    fs::rename(&path, &bad_path)?;
+                    self.tables.lock().unwrap().remove(&region);
+                    self.with_table(region, |table| self.write_slots(region, table, &blobs))?;
+                }
+                result => result?,
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Empties the chunks' slots, so they're generated again when they next
+    /// load. The blobs become dead space until the region is compacted.
+    pub fn remove_chunks(&self, coords: impl Iterator<Item = ChunkCoord>) -> io::Result<()> {
+        let mut regions: HashMap<ChunkCoord, Vec<usize>> = HashMap::new();
+        for coord in coords {
+            let (region, slot) = self.locate(coord);
+            regions.entry(region).or_insert_with(Vec::new).push(slot);
+        }
+
+        for (region, slots) in regions {
+            self.with_table(region, |table| {
+                let slots: Vec<usize> = slots.into_iter().filter(|slot| table[*slot].0 != 0).collect();
+                if slots.is_empty() {
+                    return Ok(());
+                }
+
+                let mut file = OpenOptions::new().write(true).open(self.region_path(region))?;
+                for slot in slots {
+                    file.seek(SeekFrom::Start(REGION_HEADER_SIZE + slot as u64 * 8))?;
+                    file.write_all(&[0; 8])?;
+                    table[slot] = (0, 0);
+                }
+                Ok(())
+            })?;
+        }
+
+        Ok(())
+    }
+
+    /// Appends the blobs to the region and points their slots at them,
+    /// compacting the region instead once most of it would be dead space.
+    fn write_slots(&self, region: ChunkCoord, table: &mut OffsetTable, blobs: &[(usize, Vec<u8>)]) -> io::Result<()> {
+        let path = self.region_path(region);
+        let file_length = match fs::metadata(&path) {
+            Ok(metadata) => metadata.len(),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => 0,
+            Err(err) => return Err(err),
+        };
+
+        let mut live: u64 = table.iter().map(|(_, length)| *length as u64).sum();
+        for (slot, blob) in blobs.iter() {
+            live = live - table[*slot].1 as u64 + blob.len() as u64;
+        }
+        let appended: u64 = blobs.iter().map(|(_, blob)| blob.len() as u64).sum();
+
+        if file_length == 0 || file_length + appended > self.data_start() + live * 2 {
+            let mut all: Vec<Option<Vec<u8>>> = vec![None; self.slot_count()];
+            if file_length > 0 {
+                let mut file = File::open(&path)?;
+                for (slot, (offset, length)) in table.iter().enumerate() {
+                    if *offset != 0 {
+                        all[slot] = Some(Self::read_blob(&mut file, *offset, *length)?);
+                    }
+                }
+            }
+            for (slot, blob) in blobs.iter() {
+                all[*slot] = Some(blob.clone());
+            }
+
+            *table = self.write_region(region, &all)?;
+            return Ok(());
+        }
+
+        let mut file = OpenOptions::new().write(true).open(&path)?;
+        let mut offset = file_length;
+        let mut entries = Vec::with_capacity(blobs.len());
+        file.seek(SeekFrom::Start(offset))?;
+        for (slot, blob) in blobs.iter() {
+            file.write_all(blob)?;
+            entries.push((*slot, (offset as u32, blob.len() as u32)));
+            offset += blob.len() as u64;
+        }
+        // The blobs have to be on disk before any slot points at them
+        file.sync_data()?;
+
+        for (slot, entry) in entries {
+            file.seek(SeekFrom::Start(REGION_HEADER_SIZE + slot as u64 * 8))?;
+            file.write_all(&entry.0.to_le_bytes())?;
+            file.write_all(&entry.1.to_le_bytes())?;
+            table[slot] = entry;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chunk::test_util::settings;
+
+    fn store(name: &str) -> RegionStore {
+        let directory = std::env::temp_dir().join(format!("region-test-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&directory);
+        RegionStore::new(directory)
+    }
+
+    fn filled(chunk_settings: &ChunkSettings, value: f32) -> Chunk {
+        let mut chunk = Chunk::new(chunk_settings);
+        chunk.data[3][4][5] = value;
+        chunk.materials[1][2][3] = 7;
+        chunk
+    }
+
+    #[test]
+    fn saved_chunks_load_back() {
+        let chunk_settings = settings();
+        let store = store("roundtrip");
+        let a = filled(&chunk_settings, 1.5);
+        let b = filled(&chunk_settings, -2.5);
+        store
+            .save_chunks(
+                &chunk_settings,
+                vec![(ChunkCoord::new(0, 0, 0), &a), (ChunkCoord::new(-1, 3, 9), &b)].into_iter(),
+            )
+            .unwrap();
+
+        // A fresh store has to read the tables from disk
+        let reopened = RegionStore::new(store.directory.clone());
+        let loaded = reopened.load_chunk(&chunk_settings, ChunkCoord::new(-1, 3, 9)).unwrap().unwrap();
+        assert_eq!(loaded.chunk.data[3][4][5], -2.5);
+        assert_eq!(loaded.chunk.materials[1][2][3], 7);
+        assert!(reopened.load_chunk(&chunk_settings, ChunkCoord::new(1, 0, 0)).unwrap().is_none());
+
+        fs::remove_dir_all(&store.directory).unwrap();
+    }
+
+    #[test]
+    fn saved_chunks_are_listed() {
+        let chunk_settings = settings();
+        let store = store("listing");
+        assert!(store.saved_chunks().unwrap().is_empty());
+
+        let chunk = filled(&chunk_settings, 1.5);
+        let coords = vec![
+            ChunkCoord::new(0, 0, 0),
+            ChunkCoord::new(-1, 3, 9),
+            ChunkCoord::new(7, -8, 2),
+        ];
+        store
+            .save_chunks(&chunk_settings, coords.iter().map(|coord| (*coord, &chunk)))
+            .unwrap();
+
+        let mut saved = RegionStore::new(store.directory.clone()).saved_chunks().unwrap();
+        saved.sort_by_key(|coord| (coord.x, coord.y, coord.z));
+        let mut expected = coords;
+        expected.sort_by_key(|coord| (coord.x, coord.y, coord.z));
+        assert_eq!(saved, expected);
+    }
+
+    #[test]
+    fn removed_chunks_are_generated_again() {
+        let chunk_settings = settings();
+        let store = store("remove");
+        let chunk = filled(&chunk_settings, 1.5);
+        let (kept, removed) = (ChunkCoord::new(0, 0, 0), ChunkCoord::new(1, 0, 0));
+        store
+            .save_chunks(&chunk_settings, vec![(kept, &chunk), (removed, &chunk)].into_iter())
+            .unwrap();
+
+        store
+            .remove_chunks(vec![removed, ChunkCoord::new(40, 0, 0)].into_iter())
+            .unwrap();
+
+        let reopened = RegionStore::new(store.directory.clone());
+        assert!(reopened.load_chunk(&chunk_settings, removed).unwrap().is_none());
+        assert!(reopened.load_chunk(&chunk_settings, kept).unwrap().is_some());
+    }
+
+    #[test]
+    fn resaving_a_chunk_keeps_its_neighbours() {
+        let chunk_settings = settings();
+        let store = store("resave");
+        let a = filled(&chunk_settings, 1.0);
+        let b = filled(&chunk_settings, 2.0);
+        store
+            .save_chunks(
+                &chunk_settings,
+                vec![(ChunkCoord::new(0, 0, 0), &a), (ChunkCoord::new(0, 0, 1), &b)].into_iter(),
+            )
+            .unwrap();
+
+        for value in 0..10 {
+            let a = filled(&chunk_settings, value as f32);
+            store
+                .save_chunks(&chunk_settings, std::iter::once((ChunkCoord::new(0, 0, 0), &a)))
+                .unwrap();
+        }
+
+        let reopened = RegionStore::new(store.directory.clone());
+        let a = reopened.load_chunk(&chunk_settings, ChunkCoord::new(0, 0, 0)).unwrap().unwrap();
+        let b = reopened.load_chunk(&chunk_settings, ChunkCoord::new(0, 0, 1)).unwrap().unwrap();
+        assert_eq!(a.chunk.data[3][4][5], 9.0);
+        assert_eq!(b.chunk.data[3][4][5], 2.0);
+
+        fs::remove_dir_all(&store.directory).unwrap();
+    }
+
+    #[test]
+    fn unreadable_region_is_moved_aside() {
+        let chunk_settings = settings();
+        let store = store("corrupt");
+        fs::create_dir_all(&store.directory).unwrap();
+        let path = store.region_path(ChunkCoord::new(0, 0, 0));
+        fs::write(&path, b"not a region").unwrap();
+
+        assert!(store.load_chunk(&chunk_settings, ChunkCoord::new(0, 0, 0)).is_err());
+
+        let chunk = filled(&chunk_settings, 3.0);
+        store
+            .save_chunks(&chunk_settings, std::iter::once((ChunkCoord::new(0, 0, 0), &chunk)))
+            .unwrap();
+
+        assert_eq!(fs::read(path.with_extension("region.bad")).unwrap(), b"not a region");
+        let loaded = store.load_chunk(&chunk_settings, ChunkCoord::new(0, 0, 0)).unwrap().unwrap();
+        assert_eq!(loaded.chunk.data[3][4][5], 3.0);
+
+        fs::remove_dir_all(&store.directory).unwrap();
+    }
+}
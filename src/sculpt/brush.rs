@@ -0,0 +1,265 @@
+use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use super::edit::EditMode;
+use crate::chunk::{pipeline::MATERIAL_PALETTE, raycast::TerrainHit};
+
+/// Number of colors in the terrain shader's material palette.
+pub const MATERIAL_COUNT: u8 = MATERIAL_PALETTE.len() as u8;
+
+/// Volume a brush affects, centered on the brush position.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub enum BrushShape {
+    Sphere { radius: f32 },
+    Cube { half_size: f32 },
+    Cylinder { radius: f32, half_height: f32 },
+    /// Base on the brush position, tip pointing up.
+    Cone { radius: f32, height: f32 },
+}
+
+impl BrushShape {
+    /// Signed distance from the shape's surface, negative inside.
+    pub fn distance(&self, offset: Vec3) -> f32 {
+        match *self {
+            BrushShape::Sphere { radius } => offset.length() - radius,
+            BrushShape::Cube { half_size } => {
+                let q = offset.abs() - Vec3::splat(half_size);
+                q.max(Vec3::zero()).length() + q.max_element().min(0.0)
+            }
+            BrushShape::Cylinder { radius, half_height } => {
+                let radial = Vec2::new(offset.x, offset.z).length() - radius;
+                let vertical = offset.y.abs() - half_height;
+                Vec2::new(radial.max(0.0), vertical.max(0.0)).length() + radial.max(vertical).min(0.0)
+            }
+            BrushShape::Cone { radius, height } => {
+                // Distance to the slanted side is scaled by the cone's slope,
+                // close enough to exact for blending densities
+                let radial = Vec2::new(offset.x, offset.z).length();
+                let side = (radial - radius * (1.0 - offset.y / height)) * height / (height * height + radius * radius).sqrt();
+                side.max(-offset.y).max(offset.y - height)
+            }
+        }
+    }
+
+    /// Distance from the center to the far edge of the shape, used to
+    /// normalize the falloff.
+    pub fn radius(&self) -> f32 {
+        match *self {
+            BrushShape::Sphere { radius } => radius,
+            BrushShape::Cube { half_size } => half_size,
+            BrushShape::Cylinder { radius, .. } => radius,
+            BrushShape::Cone { radius, .. } => radius,
+        }
+    }
+
+    /// Same shape scaled to a new radius.
+    pub fn with_radius(&self, new_radius: f32) -> BrushShape {
+        let scale = new_radius / self.radius();
+        match *self {
+            BrushShape::Sphere { .. } => BrushShape::Sphere { radius: new_radius },
+            BrushShape::Cube { .. } => BrushShape::Cube { half_size: new_radius },
+            BrushShape::Cylinder { half_height, .. } => BrushShape::Cylinder {
+                radius: new_radius,
+                half_height: half_height * scale,
+            },
+            BrushShape::Cone { height, .. } => BrushShape::Cone {
+                radius: new_radius,
+                height: height * scale,
+            },
+        }
+    }
+
+    /// Local space bounds of the shape.
+    pub fn bounds(&self) -> (Vec3, Vec3) {
+        match *self {
+            BrushShape::Sphere { radius } => (Vec3::splat(-radius), Vec3::splat(radius)),
+            BrushShape::Cube { half_size } => (Vec3::splat(-half_size), Vec3::splat(half_size)),
+            BrushShape::Cylinder { radius, half_height } => (
+                Vec3::new(-radius, -half_height, -radius),
+                Vec3::new(radius, half_height, radius),
+            ),
+            BrushShape::Cone { radius, height } => (Vec3::new(-radius, 0.0, -radius), Vec3::new(radius, height, radius)),
+        }
+    }
+
+    /// The next shape with the same radius, for cycling through them.
+    fn next(&self) -> BrushShape {
+        let radius = self.radius();
+        match *self {
+            BrushShape::Sphere { .. } => BrushShape::Cube { half_size: radius },
+            BrushShape::Cube { .. } => BrushShape::Cylinder {
+                radius,
+                half_height: radius,
+            },
+            BrushShape::Cylinder { .. } => BrushShape::Cone {
+                radius,
+                height: radius * 2.0,
+            },
+            BrushShape::Cone { .. } => BrushShape::Sphere { radius },
+        }
+    }
+}
+
+/// How the brush weakens from its center to its edge.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum Falloff {
+    Constant,
+    Linear,
+    Smooth,
+    Gaussian,
+}
+
+impl Default for Falloff {
+    fn default() -> Self {
+        Falloff::Constant
+    }
+}
+
+impl Falloff {
+    /// Weight at `t`, 0 at the brush center and 1 on its edge.
+    pub fn weight(&self, t: f32) -> f32 {
+        let t = t.max(0.0).min(1.0);
+        match self {
+            Falloff::Constant => 1.0,
+            Falloff::Linear => 1.0 - t,
+            Falloff::Smooth => 1.0 - t * t * (3.0 - 2.0 * t),
+            Falloff::Gaussian => (-4.0 * t * t).exp(),
+        }
+    }
+
+    fn next(&self) -> Falloff {
+        match self {
+            Falloff::Constant => Falloff::Linear,
+            Falloff::Linear => Falloff::Smooth,
+            Falloff::Smooth => Falloff::Gaussian,
+            Falloff::Gaussian => Falloff::Constant,
+        }
+    }
+}
+
+/// What the brush does to the terrain.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SculptTool {
+    /// Left click digs, right click raises.
+    Sculpt,
+    Smooth,
+    Flatten,
+    Noise,
+    Paint,
+}
+
+/// The brush used for sculpting.
+pub struct TerrainBrush {
+    pub tool: SculptTool,
+    pub shape: BrushShape,
+    /// How far one application blends the terrain towards the brush shape,
+    /// between 0 and 1.
+    pub strength: f32,
+    /// Applications per second while a mouse button is held.
+    pub rate: f32,
+    pub falloff: Falloff,
+    pub noise_frequency: f32,
+    /// Material the paint tool applies.
+    pub material: u8,
+}
+
+impl Default for TerrainBrush {
+    fn default() -> Self {
+        Self {
+            tool: SculptTool::Sculpt,
+            shape: BrushShape::Sphere { radius: 3.0 },
+            strength: 0.5,
+            rate: 10.0,
+            falloff: Falloff::Smooth,
+            noise_frequency: 0.3,
+            material: 1,
+        }
+    }
+}
+
+impl TerrainBrush {
+    /// Strength of one application covering `delta_seconds`. Compounds the
+    /// same as `rate` applications per second would, so a late application
+    /// catches up instead of slowing sculpting down.
+    pub fn frame_strength(&self, delta_seconds: f32) -> f32 {
+        let strength = self.strength.max(0.0).min(1.0);
+        1.0 - (1.0 - strength).powf(self.rate * delta_seconds)
+    }
+
+    /// Edit mode for the current tool at a hit. `secondary` is the right
+    /// mouse button.
+    pub fn mode(&self, hit: &TerrainHit, secondary: bool) -> EditMode {
+        match self.tool {
+            SculptTool::Sculpt if secondary => EditMode::Raise,
+            SculptTool::Sculpt => EditMode::Lower,
+            SculptTool::Smooth => EditMode::Smooth,
+            SculptTool::Flatten => EditMode::Flatten {
+                normal: [hit.normal.x, hit.normal.y, hit.normal.z],
+            },
+            SculptTool::Noise => EditMode::Noise {
+                frequency: self.noise_frequency,
+            },
+            // Right click paints the default material back
+            SculptTool::Paint => EditMode::Paint {
+                material: if secondary { 0 } else { self.material },
+            },
+        }
+    }
+}
+
+/// Which brush button is held: `Some(false)` for the left, `Some(true)` for
+/// the right. Left wins when both are held.
+pub fn held_button(mouse_button_input: &Input<MouseButton>) -> Option<bool> {
+    if mouse_button_input.pressed(MouseButton::Left) {
+        Some(false)
+    } else if mouse_button_input.pressed(MouseButton::Right) {
+        Some(true)
+    } else {
+        None
+    }
+}
+
+/// 1 to 5 pick the tool, M cycles the paint material, B the shape and F the
+/// falloff. `[` and `]` change the radius and with shift held the strength.
+pub fn adjust_brush(keyboard_input: Res<Input<KeyCode>>, mut brush: ResMut<TerrainBrush>) {
+    let shift = keyboard_input.pressed(KeyCode::LShift) || keyboard_input.pressed(KeyCode::RShift);
+
+    let tools = [
+        (KeyCode::Key1, SculptTool::Sculpt),
+        (KeyCode::Key2, SculptTool::Smooth),
+        (KeyCode::Key3, SculptTool::Flatten),
+        (KeyCode::Key4, SculptTool::Noise),
+        (KeyCode::Key5, SculptTool::Paint),
+    ];
+    for (key, tool) in tools.iter() {
+        if keyboard_input.just_pressed(*key) {
+            brush.tool = *tool;
+        }
+    }
+
+    if keyboard_input.just_pressed(KeyCode::M) {
+        brush.material = brush.material % (MATERIAL_COUNT - 1) + 1;
+    }
+
+    if keyboard_input.just_pressed(KeyCode::B) {
+        brush.shape = brush.shape.next();
+    }
+    if keyboard_input.just_pressed(KeyCode::F) {
+        brush.falloff = brush.falloff.next();
+    }
+
+    let direction = if keyboard_input.just_pressed(KeyCode::RBracket) {
+        1.0
+    } else if keyboard_input.just_pressed(KeyCode::LBracket) {
+        -1.0
+    } else {
+        return;
+    };
+
+    if shift {
+        brush.strength = (brush.strength + direction * 0.1).max(0.1).min(1.0);
+    } else {
+        let radius = (brush.shape.radius() + direction).max(1.0).min(16.0);
+        brush.shape = brush.shape.with_radius(radius);
+    }
+}
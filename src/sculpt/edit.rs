@@ -0,0 +1,362 @@
+use std::ops::DerefMut;
+
+use bevy::prelude::*;
+use noise::{NoiseFn, Perlin};
+use serde::{Deserialize, Serialize};
+
+use super::brush::{BrushShape, Falloff};
+use crate::chunk::{
+    map::ChunkCoord,
+    terrain::{voxel_position, voxels_in_region, TerrainView},
+    Chunk, ChunkSettings,
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum EditMode {
+    /// Adds ground by lowering the density.
+    Raise,
+    /// Digs by raising the density.
+    Lower,
+    /// Blends every sample towards the average of its neighbours.
+    Smooth,
+    /// Pulls the surface onto the plane through the brush position.
+    Flatten { normal: [f32; 3] },
+    /// Adds Perlin noise detail to the surface.
+    Noise { frequency: f32 },
+    /// Sets the material without touching the densities.
+    Paint { material: u8 },
+}
+
+/// Density and material of one voxel before and after an edit.
+#[derive(Clone, Copy, Debug)]
+pub struct SampleChange {
+    pub voxel: [i32; 3],
+    pub before: f32,
+    pub after: f32,
+    pub material_before: u8,
+    pub material_after: u8,
+}
+
+/// Version new edits are recorded with. Journals keep edits of every
+/// version, and each is replayed the way it was applied when recorded:
+///
+/// 1. Raise and lower add or remove `strength` from every voxel inside the
+///    brush sphere, from before brushes had shapes and falloffs.
+/// 2. Every mode blends towards its target weighted by the falloff.
+pub const EDIT_VERSION: u32 = 2;
+
+fn unversioned_edit() -> u32 {
+    1
+}
+
+/// A single terrain modification, everything needed to apply it again.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TerrainEdit {
+    /// How the edit is applied, see `EDIT_VERSION`.
+    #[serde(default = "unversioned_edit")]
+    pub version: u32,
+    /// Brush stroke the edit belongs to, 0 for edits outside any stroke.
+    #[serde(default)]
+    pub stroke: u64,
+    pub shape: BrushShape,
+    #[serde(default)]
+    pub falloff: Falloff,
+    pub position: [f32; 3],
+    pub strength: f32,
+    pub mode: EditMode,
+    /// Material given to voxels the edit turns solid.
+    #[serde(default)]
+    pub material: Option<u8>,
+    /// Seconds since the unix epoch, for audit only, replay ignores it.
+    pub timestamp: f64,
+}
+
+impl TerrainEdit {
+    pub fn center(&self) -> Vec3 {
+        Vec3::new(self.position[0], self.position[1], self.position[2])
+    }
+
+    /// World space box of every voxel the edit can change. Raise and lower
+    /// reach one voxel past the brush so the surface just outside follows.
+    pub fn bounds(&self) -> (Vec3, Vec3) {
+        let (min, max) = self.shape.bounds();
+        let center = self.center();
+        (center + min - Vec3::one(), center + max + Vec3::one())
+    }
+
+    /// Whether the result depends on voxels next to the ones it changes,
+    /// which a chunk replayed on its own can't see on its border.
+    pub fn reads_neighbours(&self) -> bool {
+        matches!(self.mode, EditMode::Smooth)
+    }
+
+    /// Whether the edit can change any sample of the chunk.
+    pub fn overlaps(&self, chunk_settings: &ChunkSettings, coord: ChunkCoord) -> bool {
+        let (min, max) = self.bounds();
+        let chunk_min = coord.origin(chunk_settings);
+        let chunk_max = chunk_min
+            + Vec3::new(
+                (chunk_settings.width - 1) as f32,
+                (chunk_settings.height - 1) as f32,
+                (chunk_settings.length - 1) as f32,
+            );
+
+        min.x <= chunk_max.x
+            && max.x >= chunk_min.x
+            && min.y <= chunk_max.y
+            && max.y >= chunk_min.y
+            && min.z <= chunk_max.z
+            && max.z >= chunk_min.z
+    }
+
+    /// Runs the edit's mode over every voxel of the view near the brush,
+    /// weighted by the falloff. Raise and lower blend towards the brush's
+    /// signed distance field, so the surface follows the shape instead of
+    /// stepping per voxel. Every new value is computed before any is
+    /// written, and border voxels are written to all chunks sharing them.
+    /// Returns every voxel it changed.
+    pub fn apply<C: DerefMut<Target = Chunk>>(&self, terrain: &mut TerrainView<C>) -> Vec<SampleChange> {
+        if self.version < 2 {
+            return self.apply_additive(terrain);
+        }
+
+        let center = self.center();
+        let radius = self.shape.radius();
+        let threshold = terrain.chunk_settings().threshold;
+        let margin = match self.mode {
+            EditMode::Raise | EditMode::Lower => 1.0,
+            _ => 0.0,
+        };
+        let perlin = Perlin::new();
+        let normal = match self.mode {
+            EditMode::Flatten { normal } => plane_normal(normal),
+            _ => Vec3::unit_y(),
+        };
+
+        let (min, max) = self.bounds();
+        let mut changes = Vec::new();
+        for voxel in voxels_in_region(min, max) {
+            let position = voxel_position(voxel);
+            let offset = position - center;
+            let distance = self.shape.distance(offset);
+            if distance > margin {
+                continue;
+            }
+
+            let (before, material_before) = match (terrain.voxel(voxel), terrain.material(voxel)) {
+                (Some(density), Some(material)) => (density, material),
+                _ => continue,
+            };
+
+            // The falloff spans the margin too, so it fades out on the last
+            // voxel the edit reaches rather than on the brush surface
+            let weight = self.strength * self.falloff.weight((radius + distance) / (radius + margin));
+            let weight = weight.max(0.0).min(1.0);
+            let density = threshold + distance.max(-1.0);
+
+            let mut material_after = material_before;
+            let target = match self.mode {
+                EditMode::Raise => before.min(density),
+                EditMode::Lower => before.max(2.0 * threshold - density),
+                EditMode::Smooth => neighbour_average(terrain, voxel).unwrap_or(before),
+                EditMode::Flatten { .. } => threshold + offset.dot(normal).max(-1.0).min(1.0),
+                EditMode::Noise { frequency } => {
+                    let position = position * frequency;
+                    before + perlin.get([position.x as f64, position.y as f64, position.z as f64]) as f32
+                }
+                EditMode::Paint { material } => {
+                    // Air keeps its material, only the ground shows paint
+                    if weight > 0.0 && before <= threshold {
+                        material_after = material;
+                    }
+                    before
+                }
+            };
+            let after = before + (target - before) * weight;
+            if let Some(material) = self.material {
+                if before > threshold && after <= threshold {
+                    material_after = material;
+                }
+            }
+
+            if after != before || material_after != material_before {
+                changes.push(SampleChange {
+                    voxel,
+                    before,
+                    after,
+                    material_before,
+                    material_after,
+                });
+            }
+        }
+
+        for change in changes.iter() {
+            terrain.set_voxel(change.voxel, change.after);
+            terrain.set_material(change.voxel, change.material_after);
+        }
+
+        changes
+    }
+
+    /// Version 1 raise and lower: a flat step of `strength` inside the brush.
+    fn apply_additive<C: DerefMut<Target = Chunk>>(&self, terrain: &mut TerrainView<C>) -> Vec<SampleChange> {
+        let step = match self.mode {
+            EditMode::Raise => -self.strength,
+            EditMode::Lower => self.strength,
+            _ => return Vec::new(),
+        };
+
+        let (min, max) = self.bounds();
+        let mut changes = Vec::new();
+        for voxel in voxels_in_region(min, max) {
+            if self.shape.distance(voxel_position(voxel) - self.center()) >= 0.0 {
+                continue;
+            }
+
+            if let (Some(before), Some(material)) = (terrain.voxel(voxel), terrain.material(voxel)) {
+                terrain.set_voxel(voxel, before + step);
+                changes.push(SampleChange {
+                    voxel,
+                    before,
+                    after: before + step,
+                    material_before: material,
+                    material_after: material,
+                });
+            }
+        }
+
+        changes
+    }
+}
+
+/// Unit normal of a flatten plane. A zero or non finite normal has no
+/// direction and would put NaN into the densities, it flattens level ground
+/// instead.
+fn plane_normal(normal: [f32; 3]) -> Vec3 {
+    let normal = Vec3::new(normal[0], normal[1], normal[2]);
+    let length_squared = normal.length_squared();
+    if length_squared.is_finite() && length_squared > 1e-6 {
+        normal.normalize()
+    } else {
+        Vec3::unit_y()
+    }
+}
+
+/// Average density of the loaded face neighbours of a voxel.
+fn neighbour_average<C: DerefMut<Target = Chunk>>(terrain: &TerrainView<C>, voxel: [i32; 3]) -> Option<f32> {
+    let mut sum = 0.0;
+    let mut count = 0;
+
+    for axis in 0..3 {
+        for &step in [-1, 1].iter() {
+            let mut neighbour = voxel;
+            neighbour[axis] += step;
+            if let Some(value) = terrain.voxel(neighbour) {
+                sum += value;
+                count += 1;
+            }
+        }
+    }
+
+    if count == 0 {
+        None
+    } else {
+        Some(sum / count as f32)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chunk::test_util::{air, settings};
+
+    fn raise(falloff: Falloff) -> TerrainEdit {
+        TerrainEdit {
+            version: EDIT_VERSION,
+            stroke: 0,
+            shape: BrushShape::Sphere { radius: 3.0 },
+            falloff,
+            position: [8.0, 8.0, 8.0],
+            strength: 1.0,
+            mode: EditMode::Raise,
+            material: None,
+            timestamp: 0.0,
+        }
+    }
+
+    #[test]
+    fn raise_fills_the_brush() {
+        let chunk_settings = settings();
+        let mut chunk = air(&chunk_settings);
+        let mut terrain = TerrainView::detached(&chunk_settings, ChunkCoord::new(0, 0, 0), &mut chunk);
+
+        raise(Falloff::Constant).apply(&mut terrain);
+        assert!(terrain.voxel([8, 8, 8]).unwrap() <= 0.0);
+        assert!(terrain.voxel([8, 10, 8]).unwrap() <= 0.0);
+        assert_eq!(terrain.voxel([8, 13, 8]), Some(1.0));
+    }
+
+    #[test]
+    fn margin_follows_a_fading_falloff() {
+        let chunk_settings = settings();
+        let mut chunk = air(&chunk_settings);
+        let mut terrain = TerrainView::detached(&chunk_settings, ChunkCoord::new(0, 0, 0), &mut chunk);
+
+        let changes = raise(Falloff::Linear).apply(&mut terrain);
+        // Just outside the sphere, where a fading falloff used to reach 0
+        let outside = [9, 8, 11];
+        assert!(changes.iter().any(|change| change.voxel == outside));
+        assert!(terrain.voxel(outside).unwrap() < 1.0);
+    }
+
+    #[test]
+    fn unversioned_edits_apply_additively() {
+        let line = "(stroke:0,shape:Sphere(radius:2.0),position:(8.0,8.0,8.0),strength:0.25,mode:Lower,timestamp:0.0)";
+        let edit: TerrainEdit = ron::de::from_str(line).unwrap();
+        assert_eq!(edit.version, 1);
+
+        let chunk_settings = settings();
+        let mut chunk = Chunk::new(&chunk_settings);
+        let mut terrain = TerrainView::detached(&chunk_settings, ChunkCoord::new(0, 0, 0), &mut chunk);
+        edit.apply(&mut terrain);
+
+        assert_eq!(terrain.voxel([8, 8, 8]), Some(0.25));
+        assert_eq!(terrain.voxel([8, 9, 8]), Some(0.25));
+        // Exactly on the sphere isn't inside it
+        assert_eq!(terrain.voxel([8, 10, 8]), Some(0.0));
+    }
+
+    #[test]
+    fn flatten_without_a_normal_levels_the_ground() {
+        let chunk_settings = settings();
+        let mut chunk = air(&chunk_settings);
+        let mut terrain = TerrainView::detached(&chunk_settings, ChunkCoord::new(0, 0, 0), &mut chunk);
+
+        let mut edit = raise(Falloff::Constant);
+        edit.mode = EditMode::Flatten { normal: [0.0, 0.0, 0.0] };
+        let changes = edit.apply(&mut terrain);
+        assert!(!changes.is_empty());
+        assert!(changes.iter().all(|change| change.after.is_finite()));
+        assert!(terrain.voxel([8, 7, 8]).unwrap() <= 0.0);
+    }
+
+    #[test]
+    fn paint_leaves_air_alone() {
+        let chunk_settings = settings();
+        let mut chunk = air(&chunk_settings);
+        for plane in chunk.data.iter_mut() {
+            for row in plane[..8].iter_mut() {
+                for density in row.iter_mut() {
+                    *density = -1.0;
+                }
+            }
+        }
+        let mut terrain = TerrainView::detached(&chunk_settings, ChunkCoord::new(0, 0, 0), &mut chunk);
+
+        let mut edit = raise(Falloff::Constant);
+        edit.mode = EditMode::Paint { material: 3 };
+        edit.apply(&mut terrain);
+        assert_eq!(terrain.material([8, 7, 8]), Some(3));
+        assert_eq!(terrain.material([8, 9, 8]), Some(0));
+    }
+}
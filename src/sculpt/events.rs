@@ -0,0 +1,129 @@
+use bevy::prelude::*;
+
+use super::brush::{BrushShape, Falloff};
+use super::edit::{EditMode, SampleChange, TerrainEdit, EDIT_VERSION};
+use super::undo::UndoHistory;
+use super::unix_timestamp;
+use crate::chunk::{
+    map::{ChunkCoord, ChunkMap},
+    terrain::TerrainView,
+    Chunk, ChunkSettings,
+};
+use crate::persistence::EditJournal;
+
+/// Asks for a terrain modification. Any system can send one, they're all
+/// applied in the `TERRAIN_EDIT` stage after `UPDATE`.
+#[derive(Clone, Debug)]
+pub struct TerrainEditEvent {
+    pub shape: BrushShape,
+    pub position: Vec3,
+    pub operation: EditMode,
+    pub strength: f32,
+    pub falloff: Falloff,
+    /// Material given to voxels the edit turns solid.
+    pub material: Option<u8>,
+    /// Undo stroke the edit belongs to. Edits outside a stroke can't be
+    /// undone.
+    pub stroke: Option<u64>,
+}
+
+impl TerrainEditEvent {
+    pub fn new(shape: BrushShape, position: Vec3, operation: EditMode, strength: f32) -> Self {
+        TerrainEditEvent {
+            shape,
+            position,
+            operation,
+            strength,
+            falloff: Falloff::Constant,
+            material: None,
+            stroke: None,
+        }
+    }
+}
+
+/// Sent after a `TerrainEditEvent` was applied.
+#[derive(Clone, Debug)]
+pub struct TerrainEdited {
+    pub edit: TerrainEdit,
+    /// Loaded chunks the edit changed.
+    pub chunks: Vec<ChunkCoord>,
+    pub changes: Vec<SampleChange>,
+}
+
+/// Whether `TerrainEditEvent`s are applied here. A network client sends
+/// them to the server instead and waits for the result.
+pub struct TerrainAuthority {
+    pub local: bool,
+}
+
+impl Default for TerrainAuthority {
+    fn default() -> Self {
+        Self { local: true }
+    }
+}
+
+#[allow(clippy::too_many_arguments)] // Bevy systems take their resources as parameters
+pub fn apply_terrain_edits(
+    authority: Res<TerrainAuthority>,
+    chunk_settings: Res<ChunkSettings>,
+    chunk_map: Res<ChunkMap>,
+    edit_events: Res<Events<TerrainEditEvent>>,
+    mut edit_reader: Local<EventReader<TerrainEditEvent>>,
+    mut edited_events: ResMut<Events<TerrainEdited>>,
+    mut journal: ResMut<EditJournal>,
+    mut history: ResMut<UndoHistory>,
+    mut chunk_query: Query<(Entity, &ChunkCoord, &mut Chunk)>,
+) {
+    for event in edit_reader.iter(&edit_events) {
+        if !authority.local {
+            continue;
+        }
+
+        let edit = TerrainEdit {
+            version: EDIT_VERSION,
+            stroke: event.stroke.unwrap_or(0),
+            shape: event.shape,
+            falloff: event.falloff,
+            position: [event.position.x, event.position.y, event.position.z],
+            strength: event.strength,
+            mode: event.operation,
+            material: event.material,
+            timestamp: unix_timestamp(),
+        };
+
+        let (min, max) = edit.bounds();
+        let overlapping: Vec<ChunkCoord> = chunk_map
+            .chunks_in_aabb(&chunk_settings, min, max)
+            .into_iter()
+            .map(|(coord, _)| coord)
+            .collect();
+        let mut terrain = TerrainView::new(
+            &chunk_settings,
+            chunk_query.iter_mut().filter(|(_, coord, _)| overlapping.contains(coord)),
+        );
+
+        let changes = edit.apply(&mut terrain);
+        if changes.is_empty() {
+            continue;
+        }
+
+        let mut chunks: Vec<ChunkCoord> = Vec::new();
+        for change in changes.iter() {
+            for (coord, _) in terrain.voxel_locations(change.voxel) {
+                if overlapping.contains(&coord) && !chunks.contains(&coord) {
+                    chunks.push(coord);
+                }
+            }
+        }
+
+        if event.stroke.is_some() && event.stroke == history.stroke_id() {
+            history.record(&chunks, changes.iter().copied());
+        }
+        if edit.reads_neighbours() {
+            journal.record_samples(edit.stroke, changes.iter());
+        } else {
+            journal.record(edit.clone());
+        }
+        edited_events.send(TerrainEdited { edit, chunks, changes });
+    }
+}
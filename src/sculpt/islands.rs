@@ -0,0 +1,406 @@
+use std::collections::{HashMap, HashSet};
+use std::ops::Deref;
+
+use bevy::prelude::*;
+use bevy::render::{
+    mesh::Mesh,
+    pipeline::{PrimitiveTopology, RenderPipeline},
+};
+use bevy::tasks::{AsyncComputeTaskPool, Task};
+use bevy_rapier3d::rapier::{
+    dynamics::RigidBodyBuilder,
+    geometry::ColliderBuilder,
+    math::{Point, Real},
+};
+use futures_lite::future;
+
+use super::edit::SampleChange;
+use super::events::TerrainEdited;
+use super::undo::UndoHistory;
+use crate::chunk::{
+    build_chunk_mesh, generate_mesh,
+    map::{ChunkCoord, ChunkMap},
+    pipeline::ChunkRenderAssets,
+    terrain::TerrainView,
+    Chunk, ChunkSettings,
+};
+use crate::persistence::EditJournal;
+
+const NEIGHBOURS: [[i32; 3]; 6] = [
+    [1, 0, 0],
+    [-1, 0, 0],
+    [0, 1, 0],
+    [0, -1, 0],
+    [0, 0, 1],
+    [0, 0, -1],
+];
+
+pub struct IslandSettings {
+    /// Solid voxels at or below this height are anchored to bedrock.
+    pub bedrock: i32,
+    /// Pieces larger than this count as anchored, which keeps the flood fill
+    /// after each edit cheap.
+    pub max_island_voxels: usize,
+    /// Islands that fall below this height are despawned.
+    pub despawn_height: f32,
+}
+
+impl Default for IslandSettings {
+    fn default() -> Self {
+        Self {
+            bedrock: -32,
+            max_island_voxels: 4096,
+            despawn_height: -128.0,
+        }
+    }
+}
+
+/// A piece of terrain that broke off and fell.
+pub struct TerrainIsland {
+    /// Stroke whose edit cut the island loose. Undoing the stroke puts the
+    /// voxels back, so the island is despawned.
+    pub stroke: u64,
+}
+
+/// Colliders of new islands being built on the compute pool. Decomposing a
+/// large island takes long enough to stall a frame.
+#[derive(Default)]
+pub struct IslandColliders {
+    building: HashMap<Entity, Task<ColliderBuilder>>,
+}
+
+impl IslandColliders {
+    /// Starts decomposing the island's surface into convex pieces.
+    fn build(&mut self, task_pool: &AsyncComputeTaskPool, entity: Entity, v_pos: Vec<[f32; 3]>, indices: Vec<u32>) {
+        let task = task_pool.spawn(async move {
+            let points: Vec<Point<Real>> = v_pos.iter().map(|pos| Point::new(pos[0], pos[1], pos[2])).collect();
+            let triangles: Vec<[u32; 3]> = indices
+                .chunks_exact(3)
+                .map(|triangle| [triangle[0], triangle[1], triangle[2]])
+                .collect();
+            ColliderBuilder::convex_decomposition(&points, &triangles)
+        });
+        self.building.insert(entity, task);
+    }
+}
+
+/// Sent when terrain cut loose by an edit was removed to fall as islands.
+#[derive(Clone, Debug)]
+pub struct TerrainDetached {
+    pub stroke: u64,
+    /// Loaded chunks the removal changed.
+    pub chunks: Vec<ChunkCoord>,
+    pub changes: Vec<SampleChange>,
+}
+
+/// The solid voxels connected to `seed`, `None` if they're anchored: they
+/// reach bedrock, unloaded terrain, an already anchored voxel, or grow past
+/// the size limit.
+fn flood_fill<C: Deref<Target = Chunk>>(
+    terrain: &TerrainView<C>,
+    settings: &IslandSettings,
+    anchored: &mut HashSet<[i32; 3]>,
+    seed: [i32; 3],
+) -> Option<Vec<[i32; 3]>> {
+    let threshold = terrain.chunk_settings().threshold;
+    let mut visited: HashSet<[i32; 3]> = HashSet::new();
+    let mut stack = vec![seed];
+    visited.insert(seed);
+
+    let mut is_anchored = false;
+    'fill: while let Some(voxel) = stack.pop() {
+        if voxel[1] <= settings.bedrock || visited.len() > settings.max_island_voxels {
+            is_anchored = true;
+            break;
+        }
+
+        for offset in NEIGHBOURS.iter() {
+            let neighbour = [voxel[0] + offset[0], voxel[1] + offset[1], voxel[2] + offset[2]];
+            match terrain.voxel(neighbour) {
+                None => {
+                    is_anchored = true;
+                    break 'fill;
+                }
+                Some(density) if density <= threshold => {
+                    if anchored.contains(&neighbour) {
+                        is_anchored = true;
+                        break 'fill;
+                    }
+                    if visited.insert(neighbour) {
+                        stack.push(neighbour);
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    if is_anchored {
+        anchored.extend(visited);
+        None
+    } else {
+        Some(visited.into_iter().collect())
+    }
+}
+
+/// Spawns the island, its densities copied into a small chunk of their own
+/// with a ring of air around them. Returns the island with the vertices and
+/// indices of its surface, `None` if it has none.
+fn spawn_island<C: Deref<Target = Chunk>>(
+    commands: &mut Commands,
+    meshes: &mut Assets<Mesh>,
+    render_assets: &ChunkRenderAssets,
+    terrain: &TerrainView<C>,
+    island: &[[i32; 3]],
+    stroke: u64,
+) -> Option<(Entity, Vec<[f32; 3]>, Vec<u32>)> {
+    let chunk_settings = terrain.chunk_settings();
+    let mut min = island[0];
+    let mut max = island[0];
+    for voxel in island.iter() {
+        for axis in 0..3 {
+            min[axis] = min[axis].min(voxel[axis]);
+            max[axis] = max[axis].max(voxel[axis]);
+        }
+    }
+
+    let island_settings = ChunkSettings {
+        width: (max[0] - min[0] + 3) as usize,
+        height: (max[1] - min[1] + 3) as usize,
+        length: (max[2] - min[2] + 3) as usize,
+        threshold: chunk_settings.threshold,
+    };
+    let mut chunk = Chunk::new(&island_settings);
+    for x in chunk.data.iter_mut() {
+        for y in x.iter_mut() {
+            for density in y.iter_mut() {
+                *density = chunk_settings.threshold + 1.0;
+            }
+        }
+    }
+    for voxel in island.iter() {
+        let [x, y, z] = [
+            (voxel[0] - min[0] + 1) as usize,
+            (voxel[1] - min[1] + 1) as usize,
+            (voxel[2] - min[2] + 1) as usize,
+        ];
+        chunk.data[x][y][z] = terrain.voxel(*voxel).unwrap_or(chunk_settings.threshold);
+        chunk.materials[x][y][z] = terrain.material(*voxel).unwrap_or(0);
+    }
+
+    let (v_pos, _, _, _, indices) = generate_mesh(&island_settings, &chunk);
+    if indices.is_empty() {
+        return None;
+    }
+
+    let mut mesh = Mesh::new(PrimitiveTopology::TriangleList);
+    build_chunk_mesh(&mut mesh, &island_settings, &chunk);
+
+    let origin = Vec3::new((min[0] - 1) as f32, (min[1] - 1) as f32, (min[2] - 1) as f32);
+    commands
+        .spawn(MeshBundle {
+            mesh: meshes.add(mesh),
+            render_pipelines: RenderPipelines::from_pipelines(vec![RenderPipeline::new(
+                render_assets.pipeline.clone_weak(),
+            )]),
+            transform: Transform::from_translation(origin),
+            ..Default::default()
+        })
+        .with(render_assets.material.clone_weak())
+        .with(TerrainIsland { stroke });
+
+    Some((commands.current_entity().unwrap(), v_pos, indices))
+}
+
+/// Turns islands whose collider is built into falling bodies, where they
+/// were spawned.
+pub fn drop_built_islands(
+    commands: &mut Commands,
+    mut colliders: ResMut<IslandColliders>,
+    island_query: Query<&Transform, With<TerrainIsland>>,
+) {
+    let mut finished = Vec::new();
+    for (entity, task) in colliders.building.iter_mut() {
+        if let Some(collider) = future::block_on(future::poll_once(task)) {
+            finished.push((*entity, collider));
+        }
+    }
+
+    for (entity, collider) in finished {
+        colliders.building.remove(&entity);
+
+        // Undoing the stroke may have despawned the island in the meantime
+        let origin = match island_query.get(entity) {
+            Ok(transform) => transform.translation,
+            Err(_) => continue,
+        };
+        commands.insert(
+            entity,
+            (
+                RigidBodyBuilder::new_dynamic().translation(origin.x as Real, origin.y as Real, origin.z as Real),
+                collider,
+            ),
+        );
+    }
+}
+
+/// Looks for solid terrain an edit cut loose and turns every piece into a
+/// dynamic body. The removal is journaled and recorded with the edit's
+/// stroke, so it's replayed and undone along with the edit that caused it.
+#[allow(clippy::too_many_arguments)] // Bevy systems take their resources as parameters
+pub fn detach_islands(
+    commands: &mut Commands,
+    settings: Res<IslandSettings>,
+    chunk_settings: Res<ChunkSettings>,
+    chunk_map: Res<ChunkMap>,
+    render_assets: Res<ChunkRenderAssets>,
+    task_pool: Res<AsyncComputeTaskPool>,
+    mut colliders: ResMut<IslandColliders>,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut history: ResMut<UndoHistory>,
+    mut journal: ResMut<EditJournal>,
+    edited_events: Res<Events<TerrainEdited>>,
+    mut edited_reader: Local<EventReader<TerrainEdited>>,
+    mut detached_events: ResMut<Events<TerrainDetached>>,
+    mut chunk_query: Query<(Entity, &ChunkCoord, &mut Chunk)>,
+) {
+    let edits: Vec<TerrainEdited> = edited_reader.iter(&edited_events).cloned().collect();
+    if edits.is_empty() {
+        return;
+    }
+
+    let threshold = chunk_settings.threshold;
+    let air = threshold + 1.0;
+
+    for edited in edits {
+        // Only solid samples the edit removed can have cut something loose
+        let removed: Vec<[i32; 3]> = edited
+            .changes
+            .iter()
+            .filter(|change| change.before <= threshold && change.after > threshold)
+            .map(|change| change.voxel)
+            .collect();
+        if removed.is_empty() {
+            continue;
+        }
+
+        let mut terrain = TerrainView::new(&chunk_settings, chunk_query.iter_mut());
+        let mut anchored: HashSet<[i32; 3]> = HashSet::new();
+        let mut claimed: HashSet<[i32; 3]> = HashSet::new();
+        let mut islands: Vec<Vec<[i32; 3]>> = Vec::new();
+
+        for voxel in removed.iter() {
+            for offset in NEIGHBOURS.iter() {
+                let seed = [voxel[0] + offset[0], voxel[1] + offset[1], voxel[2] + offset[2]];
+                if anchored.contains(&seed) || claimed.contains(&seed) {
+                    continue;
+                }
+                if !terrain.voxel(seed).map_or(false, |density| density <= threshold) {
+                    continue;
+                }
+
+                if let Some(island) = flood_fill(&terrain, &settings, &mut anchored, seed) {
+                    claimed.extend(island.iter().copied());
+                    islands.push(island);
+                }
+            }
+        }
+
+        if islands.is_empty() {
+            continue;
+        }
+
+        let mut changes: Vec<SampleChange> = Vec::new();
+        for island in islands.iter() {
+            let spawned = spawn_island(commands, &mut meshes, &render_assets, &terrain, island, edited.edit.stroke);
+            if let Some((entity, v_pos, indices)) = spawned {
+                colliders.build(&task_pool, entity, v_pos, indices);
+            }
+
+            for voxel in island.iter() {
+                let before = terrain.voxel(*voxel).unwrap_or(threshold);
+                let material = terrain.material(*voxel).unwrap_or(0);
+                terrain.set_voxel(*voxel, air);
+                changes.push(SampleChange {
+                    voxel: *voxel,
+                    before,
+                    after: air,
+                    material_before: material,
+                    material_after: material,
+                });
+            }
+        }
+
+        let mut chunks: Vec<ChunkCoord> = Vec::new();
+        for change in changes.iter() {
+            for (coord, _) in terrain.voxel_locations(change.voxel) {
+                if chunk_map.contains(coord) && !chunks.contains(&coord) {
+                    chunks.push(coord);
+                }
+            }
+        }
+
+        if Some(edited.edit.stroke) == history.stroke_id() {
+            history.record(&chunks, changes.iter().copied());
+        }
+        journal.record_samples(edited.edit.stroke, changes.iter());
+        detached_events.send(TerrainDetached {
+            stroke: edited.edit.stroke,
+            chunks,
+            changes,
+        });
+    }
+}
+
+/// Despawns islands that fell out of the world.
+pub fn despawn_fallen_islands(
+    commands: &mut Commands,
+    settings: Res<IslandSettings>,
+    island_query: Query<(Entity, &GlobalTransform), With<TerrainIsland>>,
+) {
+    for (entity, transform) in island_query.iter() {
+        if transform.translation.y < settings.despawn_height {
+            commands.despawn_recursive(entity);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chunk::test_util::{air, settings};
+
+    #[test]
+    fn floating_block_is_an_island() {
+        let chunk_settings = settings();
+        let mut chunk = air(&chunk_settings);
+        for x in 6..8 {
+            for y in 6..8 {
+                for z in 6..8 {
+                    chunk.data[x][y][z] = -1.0;
+                }
+            }
+        }
+
+        let terrain = TerrainView::detached(&chunk_settings, ChunkCoord::new(0, 0, 0), &chunk);
+        let mut anchored = HashSet::new();
+        let island = flood_fill(&terrain, &IslandSettings::default(), &mut anchored, [6, 6, 6]);
+        assert_eq!(island.map(|voxels| voxels.len()), Some(8));
+        assert!(anchored.is_empty());
+    }
+
+    #[test]
+    fn pillar_reaching_unloaded_terrain_is_anchored() {
+        let chunk_settings = settings();
+        let mut chunk = air(&chunk_settings);
+        for y in 0..8 {
+            chunk.data[6][y][6] = -1.0;
+        }
+
+        let terrain = TerrainView::detached(&chunk_settings, ChunkCoord::new(0, 0, 0), &chunk);
+        let mut anchored = HashSet::new();
+        let island = flood_fill(&terrain, &IslandSettings::default(), &mut anchored, [6, 7, 6]);
+        assert!(island.is_none());
+        assert!(anchored.contains(&[6, 7, 6]));
+    }
+}
@@ -0,0 +1,122 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use bevy::prelude::*;
+
+use crate::chunk::raycast::TerrainCursor;
+use crate::persistence::EditJournal;
+
+pub mod brush;
+pub mod edit;
+pub mod events;
+pub mod islands;
+pub mod preview;
+pub mod undo;
+
+pub use brush::{held_button, BrushShape, Falloff, SculptTool, TerrainBrush};
+pub use edit::{EditMode, SampleChange, TerrainEdit};
+pub use events::{TerrainAuthority, TerrainEditEvent, TerrainEdited};
+pub use islands::{IslandSettings, TerrainDetached, TerrainIsland};
+pub use undo::UndoHistory;
+
+/// Stage applying the `TerrainEditEvent`s sent during `UPDATE`, before the
+/// changed chunks are remeshed.
+pub const TERRAIN_EDIT: &str = "terrain_edit";
+
+/// Stage detaching the terrain edits cut loose, once every edit of the frame
+/// was applied.
+pub const TERRAIN_DETACH: &str = "terrain_detach";
+
+fn unix_timestamp() -> f64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs_f64())
+        .unwrap_or(0.0)
+}
+
+/// Applies the brush `rate` times a second while a mouse button is held,
+/// starting on the press. Each application is one edit in the journal and
+/// on the network, so the rate doesn't follow the frame rate.
+#[allow(clippy::too_many_arguments)] // Bevy systems take their resources as parameters
+fn select_terrain(
+    time: Res<Time>,
+    mouse_button_input: Res<Input<MouseButton>>,
+    terrain_cursor: Res<TerrainCursor>,
+    brush: Res<TerrainBrush>,
+    mut since_edit: Local<f32>,
+    mut journal: ResMut<EditJournal>,
+    mut history: ResMut<UndoHistory>,
+    mut edit_events: ResMut<Events<TerrainEditEvent>>,
+) {
+    // A stroke lasts from pressing a button until releasing every button
+    let secondary = match held_button(&mouse_button_input) {
+        Some(secondary) => secondary,
+        None => {
+            history.end_stroke();
+            return;
+        }
+    };
+
+    let interval = 1.0 / brush.rate.max(1.0);
+    if mouse_button_input.just_pressed(MouseButton::Left) || mouse_button_input.just_pressed(MouseButton::Right) {
+        *since_edit = interval;
+    } else {
+        *since_edit += time.delta_seconds();
+    }
+    if *since_edit < interval {
+        return;
+    }
+
+    let hit = match &terrain_cursor.hit {
+        Some(hit) => hit,
+        None => return,
+    };
+
+    let stroke = match history.stroke_id() {
+        Some(stroke) => stroke,
+        None => {
+            let stroke = journal.next_stroke_id();
+            history.begin_stroke(stroke);
+            stroke
+        }
+    };
+
+    // A long frame still applies as much as the frames it covered would have
+    let elapsed = (*since_edit).min(interval * 4.0);
+    *since_edit = 0.0;
+
+    edit_events.send(TerrainEditEvent {
+        shape: brush.shape,
+        position: hit.position,
+        operation: brush.mode(hit, secondary),
+        strength: brush.frame_strength(elapsed),
+        falloff: brush.falloff,
+        material: None,
+        stroke: Some(stroke),
+    });
+}
+
+pub struct SculptPlugin;
+
+impl Plugin for SculptPlugin {
+    fn build(&self, app: &mut AppBuilder) {
+        app.add_resource(TerrainBrush::default())
+            .add_resource(UndoHistory::default())
+            .init_resource::<TerrainAuthority>()
+            .init_resource::<IslandSettings>()
+            .init_resource::<islands::IslandColliders>()
+            .add_event::<TerrainEditEvent>()
+            .add_event::<TerrainEdited>()
+            .add_event::<TerrainDetached>()
+            .add_stage_after(stage::UPDATE, TERRAIN_EDIT, SystemStage::parallel())
+            .add_stage_after(TERRAIN_EDIT, TERRAIN_DETACH, SystemStage::parallel())
+            .add_startup_system(preview::setup_brush_preview.system())
+            .add_system(brush::adjust_brush.system())
+            .add_system(preview::update_brush_preview.system())
+            .add_system(select_terrain.system())
+            .add_system(undo::undo_redo_system.system())
+            .add_system(islands::drop_built_islands.system())
+            .add_system(islands::despawn_fallen_islands.system())
+            .add_system_to_stage(TERRAIN_EDIT, events::apply_terrain_edits.system())
+            .add_system_to_stage(TERRAIN_DETACH, islands::detach_islands.system());
+    }
+}
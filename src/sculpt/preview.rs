@@ -0,0 +1,179 @@
+use std::f32::consts::PI;
+
+use bevy::prelude::*;
+use bevy::render::{mesh::Indices, pipeline::PrimitiveTopology};
+
+use super::brush::{held_button, BrushShape, SculptTool, TerrainBrush};
+use crate::chunk::{pipeline::MATERIAL_PALETTE, raycast::TerrainCursor};
+
+const PREVIEW_ALPHA: f32 = 0.3;
+
+/// Marks the translucent shape showing where the brush will apply.
+pub struct BrushPreview;
+
+/// Unit meshes for every brush shape, scaled to the brush by the preview's
+/// transform.
+pub struct BrushPreviewAssets {
+    sphere: Handle<Mesh>,
+    cube: Handle<Mesh>,
+    cylinder: Handle<Mesh>,
+    cone: Handle<Mesh>,
+    material: Handle<StandardMaterial>,
+}
+
+/// A cylinder or cone around the y axis from `bottom` to `top`.
+fn frustum_mesh(bottom_radius: f32, top_radius: f32, bottom: f32, top: f32, segments: u32) -> Mesh {
+    let mut positions: Vec<[f32; 3]> = Vec::new();
+    let mut normals: Vec<[f32; 3]> = Vec::new();
+    let mut uvs: Vec<[f32; 2]> = Vec::new();
+    let mut indices: Vec<u32> = Vec::new();
+
+    let height = top - bottom;
+    for i in 0..=segments {
+        let angle = i as f32 / segments as f32 * 2.0 * PI;
+        let (sin, cos) = angle.sin_cos();
+        let normal = Vec3::new(cos * height, bottom_radius - top_radius, sin * height).normalize();
+        let u = i as f32 / segments as f32;
+
+        positions.push([cos * bottom_radius, bottom, sin * bottom_radius]);
+        positions.push([cos * top_radius, top, sin * top_radius]);
+        normals.push(normal.into());
+        normals.push(normal.into());
+        uvs.push([u, 1.0]);
+        uvs.push([u, 0.0]);
+
+        if i < segments {
+            let base = i * 2;
+            indices.extend_from_slice(&[base, base + 1, base + 2, base + 1, base + 3, base + 2]);
+        }
+    }
+
+    let caps = [(bottom, bottom_radius, -1.0), (top, top_radius, 1.0)];
+    for (y, radius, direction) in caps.iter() {
+        if *radius <= 0.0 {
+            continue;
+        }
+
+        let center = positions.len() as u32;
+        positions.push([0.0, *y, 0.0]);
+        normals.push([0.0, *direction, 0.0]);
+        uvs.push([0.5, 0.5]);
+
+        for i in 0..=segments {
+            let angle = i as f32 / segments as f32 * 2.0 * PI;
+            let (sin, cos) = angle.sin_cos();
+            positions.push([cos * radius, *y, sin * radius]);
+            normals.push([0.0, *direction, 0.0]);
+            uvs.push([0.5 + cos * 0.5, 0.5 + sin * 0.5]);
+
+            if i < segments {
+                let ring = center + 1 + i;
+                if *direction > 0.0 {
+                    indices.extend_from_slice(&[center, ring + 1, ring]);
+                } else {
+                    indices.extend_from_slice(&[center, ring, ring + 1]);
+                }
+            }
+        }
+    }
+
+    let mut mesh = Mesh::new(PrimitiveTopology::TriangleList);
+    mesh.set_attribute(Mesh::ATTRIBUTE_POSITION, positions);
+    mesh.set_attribute(Mesh::ATTRIBUTE_NORMAL, normals);
+    mesh.set_attribute(Mesh::ATTRIBUTE_UV_0, uvs);
+    mesh.set_indices(Some(Indices::U32(indices)));
+    mesh
+}
+
+pub fn setup_brush_preview(
+    commands: &mut Commands,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut materials: ResMut<Assets<StandardMaterial>>,
+) {
+    let assets = BrushPreviewAssets {
+        sphere: meshes.add(Mesh::from(shape::Icosphere {
+            radius: 1.0,
+            subdivisions: 3,
+        })),
+        cube: meshes.add(Mesh::from(shape::Cube { size: 2.0 })),
+        cylinder: meshes.add(frustum_mesh(1.0, 1.0, -1.0, 1.0, 24)),
+        cone: meshes.add(frustum_mesh(1.0, 0.0, 0.0, 1.0, 24)),
+        material: materials.add(StandardMaterial {
+            albedo: Color::rgba(1.0, 1.0, 1.0, PREVIEW_ALPHA),
+            shaded: false,
+            ..Default::default()
+        }),
+    };
+
+    commands
+        .spawn(PbrBundle {
+            mesh: assets.sphere.clone(),
+            material: assets.material.clone(),
+            visible: Visible {
+                is_visible: false,
+                is_transparent: true,
+            },
+            ..Default::default()
+        })
+        .with(BrushPreview);
+
+    commands.insert_resource(assets);
+}
+
+/// Preview color of a tool, red digging and green raising for sculpt and
+/// the painted material for paint.
+fn preview_color(brush: &TerrainBrush, secondary: bool) -> Color {
+    match brush.tool {
+        SculptTool::Sculpt if secondary => Color::rgba(0.2, 0.9, 0.3, PREVIEW_ALPHA),
+        SculptTool::Sculpt => Color::rgba(0.9, 0.25, 0.2, PREVIEW_ALPHA),
+        SculptTool::Smooth => Color::rgba(0.3, 0.5, 1.0, PREVIEW_ALPHA),
+        SculptTool::Flatten => Color::rgba(1.0, 0.85, 0.2, PREVIEW_ALPHA),
+        SculptTool::Noise => Color::rgba(0.7, 0.35, 0.95, PREVIEW_ALPHA),
+        SculptTool::Paint => {
+            let [r, g, b] = MATERIAL_PALETTE[brush.material as usize % MATERIAL_PALETTE.len()];
+            Color::rgba(r, g, b, PREVIEW_ALPHA)
+        }
+    }
+}
+
+/// Moves the preview onto the terrain under the cursor and matches it to
+/// the brush's shape, size and tool.
+pub fn update_brush_preview(
+    mouse_button_input: Res<Input<MouseButton>>,
+    terrain_cursor: Res<TerrainCursor>,
+    brush: Res<TerrainBrush>,
+    assets: Res<BrushPreviewAssets>,
+    mut materials: ResMut<Assets<StandardMaterial>>,
+    mut preview_query: Query<(&mut Transform, &mut Visible, &mut Handle<Mesh>), With<BrushPreview>>,
+) {
+    // Only touch the material when the color changes, every mutable access
+    // re-uploads it
+    let color = preview_color(&brush, held_button(&mouse_button_input).unwrap_or(false));
+    if materials.get(&assets.material).map_or(false, |material| material.albedo != color) {
+        materials.get_mut(&assets.material).unwrap().albedo = color;
+    }
+
+    for (mut transform, mut visible, mut mesh) in preview_query.iter_mut() {
+        let hit = match &terrain_cursor.hit {
+            Some(hit) => hit,
+            None => {
+                visible.is_visible = false;
+                continue;
+            }
+        };
+
+        let (shape_mesh, scale) = match brush.shape {
+            BrushShape::Sphere { radius } => (&assets.sphere, Vec3::splat(radius)),
+            BrushShape::Cube { half_size } => (&assets.cube, Vec3::splat(half_size)),
+            BrushShape::Cylinder { radius, half_height } => (&assets.cylinder, Vec3::new(radius, half_height, radius)),
+            BrushShape::Cone { radius, height } => (&assets.cone, Vec3::new(radius, height, radius)),
+        };
+
+        if *mesh != *shape_mesh {
+            *mesh = shape_mesh.clone();
+        }
+        visible.is_visible = true;
+        transform.translation = hit.position;
+        transform.scale = scale;
+    }
+}
@@ -0,0 +1,251 @@
+use std::collections::{HashMap, HashSet};
+
+use bevy::prelude::*;
+
+use super::edit::SampleChange;
+use super::events::TerrainAuthority;
+use super::islands::TerrainIsland;
+use crate::chunk::{
+    map::{ChunkCoord, ChunkMap},
+    terrain::TerrainView,
+    Chunk, ChunkSettings, ChunksRewritten,
+};
+use crate::persistence::EditJournal;
+
+/// Every sample a single mouse down to mouse up stroke changed.
+pub struct Stroke {
+    pub id: u64,
+    /// Chunks the stroke changed. They all have to be loaded to undo or
+    /// redo it, the changes can't be written into saved chunks.
+    pub chunks: HashSet<ChunkCoord>,
+    pub changes: Vec<SampleChange>,
+}
+
+/// Undo and redo history of sculpting strokes.
+pub struct UndoHistory {
+    /// Oldest strokes are dropped once the history grows past this.
+    pub max_strokes: usize,
+    undo: Vec<Stroke>,
+    redo: Vec<Stroke>,
+    current: Option<(u64, HashSet<ChunkCoord>, HashMap<[i32; 3], SampleChange>)>,
+}
+
+impl Default for UndoHistory {
+    fn default() -> Self {
+        Self {
+            max_strokes: 64,
+            undo: Vec::new(),
+            redo: Vec::new(),
+            current: None,
+        }
+    }
+}
+
+impl UndoHistory {
+    pub fn begin_stroke(&mut self, id: u64) {
+        self.end_stroke();
+        self.current = Some((id, HashSet::new(), HashMap::new()));
+    }
+
+    /// Id of the stroke in progress, if any.
+    pub fn stroke_id(&self) -> Option<u64> {
+        self.current.as_ref().map(|(id, _, _)| *id)
+    }
+
+    /// Adds changes to the stroke in progress, `chunks` being the chunks
+    /// they were written to. A sample changed several times keeps its first
+    /// `before` and last `after` values.
+    pub fn record(&mut self, chunks: &[ChunkCoord], changes: impl IntoIterator<Item = SampleChange>) {
+        if let Some((_, stroke_chunks, samples)) = self.current.as_mut() {
+            stroke_chunks.extend(chunks.iter().copied());
+            for change in changes {
+                samples
+                    .entry(change.voxel)
+                    .and_modify(|sample| {
+                        sample.after = change.after;
+                        sample.material_after = change.material_after;
+                    })
+                    .or_insert(change);
+            }
+        }
+    }
+
+    /// Closes the stroke in progress. Strokes that changed nothing are
+    /// dropped, anything else clears the redo history.
+    pub fn end_stroke(&mut self) {
+        if let Some((id, chunks, samples)) = self.current.take() {
+            if samples.is_empty() {
+                return;
+            }
+
+            self.redo.clear();
+            self.undo.push(Stroke {
+                id,
+                chunks,
+                changes: samples.into_iter().map(|(_, change)| change).collect(),
+            });
+            if self.undo.len() > self.max_strokes {
+                self.undo.remove(0);
+            }
+        }
+    }
+
+    pub fn can_undo(&self) -> bool {
+        !self.undo.is_empty()
+    }
+
+    pub fn can_redo(&self) -> bool {
+        !self.redo.is_empty()
+    }
+}
+
+/// Writes one side of a stroke back into its chunks. Returns false without
+/// changing anything if any of them isn't loaded.
+fn restore(
+    stroke: &Stroke,
+    chunk_settings: &ChunkSettings,
+    chunk_map: &ChunkMap,
+    chunk_query: &mut Query<(Entity, &ChunkCoord, &mut Chunk)>,
+    value: impl Fn(&SampleChange) -> (f32, u8),
+) -> bool {
+    if !stroke.chunks.iter().all(|coord| chunk_map.contains(*coord)) {
+        return false;
+    }
+
+    let mut terrain = TerrainView::new(
+        chunk_settings,
+        chunk_query
+            .iter_mut()
+            .filter(|(_, coord, _)| stroke.chunks.contains(*coord)),
+    );
+    for change in stroke.changes.iter() {
+        let (density, material) = value(change);
+        terrain.set_voxel(change.voxel, density);
+        terrain.set_material(change.voxel, material);
+    }
+
+    true
+}
+
+/// Ctrl+Z undoes the last stroke, Ctrl+Y redoes it. A stroke reaching into
+/// chunks that have been unloaded since stays where it is. Undoing puts back
+/// the terrain islands the stroke cut loose, so their bodies are despawned.
+/// Network clients don't own the terrain and can't undo.
+#[allow(clippy::too_many_arguments)] // Bevy systems take their resources as parameters
+pub fn undo_redo_system(
+    commands: &mut Commands,
+    authority: Res<TerrainAuthority>,
+    keyboard_input: Res<Input<KeyCode>>,
+    chunk_settings: Res<ChunkSettings>,
+    chunk_map: Res<ChunkMap>,
+    mut history: ResMut<UndoHistory>,
+    mut journal: ResMut<EditJournal>,
+    mut rewritten_events: ResMut<Events<ChunksRewritten>>,
+    mut chunk_query: Query<(Entity, &ChunkCoord, &mut Chunk)>,
+    island_query: Query<(Entity, &TerrainIsland)>,
+) {
+    let control = keyboard_input.pressed(KeyCode::LControl) || keyboard_input.pressed(KeyCode::RControl);
+    if !authority.local || !control || history.stroke_id().is_some() {
+        return;
+    }
+
+    if keyboard_input.just_pressed(KeyCode::Z) {
+        if let Some(stroke) = history.undo.pop() {
+            let restored = restore(&stroke, &chunk_settings, &chunk_map, &mut chunk_query, |change| {
+                (change.before, change.material_before)
+            });
+            if restored {
+                for (entity, island) in island_query.iter() {
+                    if island.stroke == stroke.id {
+                        commands.despawn_recursive(entity);
+                    }
+                }
+                journal.record_undo(stroke.id, stroke.changes.iter());
+                rewritten_events.send(ChunksRewritten {
+                    chunks: stroke.chunks.iter().copied().collect(),
+                    regenerated: false,
+                });
+                history.redo.push(stroke);
+            } else {
+                warn!("can't undo, the stroke changed chunks that aren't loaded anymore");
+                history.undo.push(stroke);
+            }
+        }
+    } else if keyboard_input.just_pressed(KeyCode::Y) {
+        if let Some(stroke) = history.redo.pop() {
+            let restored = restore(&stroke, &chunk_settings, &chunk_map, &mut chunk_query, |change| {
+                (change.after, change.material_after)
+            });
+            if restored {
+                journal.record_redo(stroke.id, stroke.changes.iter());
+                rewritten_events.send(ChunksRewritten {
+                    chunks: stroke.chunks.iter().copied().collect(),
+                    regenerated: false,
+                });
+                history.undo.push(stroke);
+            } else {
+                warn!("can't redo, the stroke changed chunks that aren't loaded anymore");
+                history.redo.push(stroke);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn change(voxel: [i32; 3], before: f32, after: f32) -> SampleChange {
+        SampleChange {
+            voxel,
+            before,
+            after,
+            material_before: 0,
+            material_after: 0,
+        }
+    }
+
+    #[test]
+    fn stroke_keeps_first_before_and_last_after() {
+        let mut history = UndoHistory::default();
+        history.begin_stroke(1);
+        history.record(&[ChunkCoord::new(0, 0, 0)], vec![change([1, 1, 1], 1.0, 0.5)]);
+        history.record(&[ChunkCoord::new(1, 0, 0)], vec![change([1, 1, 1], 0.5, -1.0)]);
+        history.end_stroke();
+
+        let stroke = history.undo.last().unwrap();
+        assert_eq!(stroke.changes.len(), 1);
+        assert_eq!(stroke.changes[0].before, 1.0);
+        assert_eq!(stroke.changes[0].after, -1.0);
+        assert_eq!(stroke.chunks.len(), 2);
+    }
+
+    #[test]
+    fn empty_strokes_are_dropped() {
+        let mut history = UndoHistory::default();
+        history.begin_stroke(1);
+        history.end_stroke();
+        assert!(!history.can_undo());
+    }
+
+    #[test]
+    fn new_stroke_clears_redo_and_old_strokes_fall_off() {
+        let mut history = UndoHistory {
+            max_strokes: 2,
+            ..Default::default()
+        };
+        for id in 1..=3 {
+            history.begin_stroke(id);
+            history.record(&[], vec![change([0, 0, 0], 0.0, id as f32)]);
+            history.end_stroke();
+        }
+        assert_eq!(history.undo.iter().map(|stroke| stroke.id).collect::<Vec<_>>(), vec![2, 3]);
+
+        let stroke = history.undo.pop().unwrap();
+        history.redo.push(stroke);
+        history.begin_stroke(4);
+        history.record(&[], vec![change([0, 0, 0], 0.0, 4.0)]);
+        history.end_stroke();
+        assert!(!history.can_redo());
+    }
+}
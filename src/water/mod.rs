@@ -0,0 +1,479 @@
+use std::collections::{HashMap, HashSet};
+use std::ops::DerefMut;
+
+use bevy::prelude::*;
+use bevy::render::mesh::{Indices, Mesh, VertexAttributeValues};
+use bevy::render::pipeline::PrimitiveTopology;
+
+use crate::chunk::{
+    generate_mesh,
+    map::ChunkCoord,
+    raycast::TerrainCursor,
+    terrain::{nearest_voxel, voxel_locations},
+    Chunk, ChunkSettings,
+};
+
+const SIDEWAYS: [[i32; 3]; 4] = [[1, 0, 0], [-1, 0, 0], [0, 0, 1], [0, 0, -1]];
+
+const NEIGHBOURS: [[i32; 3]; 6] = [
+    [1, 0, 0],
+    [-1, 0, 0],
+    [0, 1, 0],
+    [0, -1, 0],
+    [0, 0, 1],
+    [0, 0, -1],
+];
+
+/// Amount of water in every sample of a chunk, indexed like `Chunk::data`.
+/// A sample holds up to 1.0 and solid samples hold none, so the terrain
+/// density acts as the walls the water flows between.
+///
+/// Water only lives for the session: it isn't saved with the chunk, and a
+/// chunk unloaded and loaded again comes back dry. It isn't synced over the
+/// network either, every peer simulates the water poured on its side.
+pub struct Water {
+    pub levels: Box<Vec<Vec<Vec<f32>>>>,
+    /// Child entity showing the water surface, spawned once there's water.
+    surface: Option<Entity>,
+}
+
+impl Water {
+    pub fn new(chunk_settings: &ChunkSettings) -> Self {
+        Water {
+            levels: Box::new(vec![
+                vec![vec![0.0; chunk_settings.length]; chunk_settings.height];
+                chunk_settings.width
+            ]),
+            surface: None,
+        }
+    }
+
+    /// The water as a density field the marching cubes extractor can mesh,
+    /// samples at least half full count as inside.
+    fn as_chunk(&self, chunk_settings: &ChunkSettings) -> Chunk {
+        let mut chunk = Chunk::new(chunk_settings);
+        for (x, plane) in self.levels.iter().enumerate() {
+            for (y, row) in plane.iter().enumerate() {
+                for (z, level) in row.iter().enumerate() {
+                    chunk.data[x][y][z] = chunk_settings.threshold + (0.5 - level).max(-1.0).min(1.0);
+                }
+            }
+        }
+        chunk
+    }
+}
+
+pub struct WaterSettings {
+    /// Seconds between simulation steps.
+    pub tick: f32,
+    /// Share of the level difference moved to each lower side neighbour per
+    /// step. Above 0.25 a sample can give away more than it has.
+    pub spread: f32,
+    /// Levels below this evaporate, so thin films don't spread forever.
+    pub min_level: f32,
+    /// Water added per second while pouring.
+    pub pour_rate: f32,
+    /// Voxels whose level moved less than this in a step count as settled
+    /// and aren't simulated again until something around them changes.
+    pub settled_flow: f32,
+}
+
+impl Default for WaterSettings {
+    fn default() -> Self {
+        Self {
+            tick: 0.1,
+            spread: 0.2,
+            min_level: 0.005,
+            pour_rate: 20.0,
+            settled_flow: 0.0005,
+        }
+    }
+}
+
+/// Water voxels that may still flow. Settled water isn't simulated until
+/// something next to it changes.
+#[derive(Default)]
+pub struct ActiveWater {
+    voxels: HashSet<[i32; 3]>,
+    /// Chunks whose terrain changed, all of their water is woken up.
+    chunks: HashSet<ChunkCoord>,
+}
+
+pub struct WaterAssets {
+    pub material: Handle<StandardMaterial>,
+}
+
+fn setup_water(commands: &mut Commands, mut materials: ResMut<Assets<StandardMaterial>>) {
+    commands.insert_resource(WaterAssets {
+        material: materials.add(StandardMaterial {
+            albedo: Color::rgba(0.15, 0.35, 0.75, 0.6),
+            ..Default::default()
+        }),
+    });
+}
+
+fn add_water(
+    commands: &mut Commands,
+    chunk_settings: Res<ChunkSettings>,
+    chunk_query: Query<Entity, (With<Chunk>, Without<Water>)>,
+) {
+    for entity in chunk_query.iter() {
+        commands.insert_one(entity, Water::new(&chunk_settings));
+    }
+}
+
+/// World space access to the water of the loaded chunks. Like
+/// `TerrainView`, a voxel on a chunk border is written to every chunk
+/// holding it.
+struct WaterView<'a, W> {
+    chunk_settings: &'a ChunkSettings,
+    chunks: HashMap<ChunkCoord, (&'a Chunk, W)>,
+}
+
+impl<'a, W: DerefMut<Target = Water>> WaterView<'a, W> {
+    fn level(&self, voxel: [i32; 3]) -> f32 {
+        for (coord, [x, y, z]) in voxel_locations(self.chunk_settings, voxel) {
+            if let Some((_, water)) = self.chunks.get(&coord) {
+                return water.levels[x][y][z];
+            }
+        }
+
+        0.0
+    }
+
+    /// Whether water can flow into the voxel: it's loaded and not solid.
+    fn is_open(&self, voxel: [i32; 3]) -> bool {
+        for (coord, [x, y, z]) in voxel_locations(self.chunk_settings, voxel) {
+            if let Some((chunk, _)) = self.chunks.get(&coord) {
+                return chunk.data[x][y][z] > self.chunk_settings.threshold;
+            }
+        }
+
+        false
+    }
+
+    fn set_level(&mut self, voxel: [i32; 3], level: f32) {
+        for (coord, [x, y, z]) in voxel_locations(self.chunk_settings, voxel) {
+            if let Some((_, water)) = self.chunks.get_mut(&coord) {
+                // Only touch chunks that change so unchanged ones aren't remeshed
+                if water.levels[x][y][z] != level {
+                    water.levels[x][y][z] = level;
+                }
+            }
+        }
+    }
+
+    fn wet_voxels(&self, coord: ChunkCoord) -> Vec<[i32; 3]> {
+        let mut voxels = Vec::new();
+        if let Some((_, water)) = self.chunks.get(&coord) {
+            let origin = coord.origin(self.chunk_settings);
+            for (x, plane) in water.levels.iter().enumerate() {
+                for (y, row) in plane.iter().enumerate() {
+                    for (z, level) in row.iter().enumerate() {
+                        if *level > 0.0 {
+                            voxels.push([
+                                origin.x as i32 + x as i32,
+                                origin.y as i32 + y as i32,
+                                origin.z as i32 + z as i32,
+                            ]);
+                        }
+                    }
+                }
+            }
+        }
+        voxels
+    }
+}
+
+/// One cellular automaton step over the active voxels: water falls into
+/// open space below, then levels out with its lower side neighbours, and
+/// anything above a full sample is pushed up. No voxel takes in more than
+/// its level plus what it was already given this step leaves room for.
+/// Voxels whose level moved wake their neighbours for the next step.
+fn step_water<W: DerefMut<Target = Water>>(
+    view: &mut WaterView<W>,
+    settings: &WaterSettings,
+    active: &mut ActiveWater,
+) {
+    for coord in active.chunks.drain() {
+        active.voxels.extend(view.wet_voxels(coord));
+    }
+
+    let mut flows: HashMap<[i32; 3], f32> = HashMap::new();
+    let mut added: HashMap<[i32; 3], f32> = HashMap::new();
+    for voxel in active.voxels.drain() {
+        let level = view.level(voxel);
+        if level <= 0.0 {
+            continue;
+        }
+        let mut remaining = level;
+
+        let below = [voxel[0], voxel[1] - 1, voxel[2]];
+        if view.is_open(below) {
+            let filled = view.level(below) + added.get(&below).copied().unwrap_or(0.0);
+            let down = remaining.min((1.0 - filled).max(0.0));
+            if down > 0.0 {
+                *flows.entry(below).or_insert(0.0) += down;
+                *added.entry(below).or_insert(0.0) += down;
+                remaining -= down;
+            }
+        }
+
+        for offset in SIDEWAYS.iter() {
+            let neighbour = [voxel[0] + offset[0], voxel[1] + offset[1], voxel[2] + offset[2]];
+            if !view.is_open(neighbour) {
+                continue;
+            }
+
+            let filled = view.level(neighbour) + added.get(&neighbour).copied().unwrap_or(0.0);
+            let difference = remaining - filled;
+            if difference > 0.0 {
+                let flow = difference * settings.spread;
+                *flows.entry(neighbour).or_insert(0.0) += flow;
+                *added.entry(neighbour).or_insert(0.0) += flow;
+                remaining -= flow;
+            }
+        }
+
+        let above = [voxel[0], voxel[1] + 1, voxel[2]];
+        if remaining > 1.0 && view.is_open(above) {
+            let up = (remaining - 1.0) * 0.5;
+            *flows.entry(above).or_insert(0.0) += up;
+            *added.entry(above).or_insert(0.0) += up;
+            remaining -= up;
+        }
+
+        *flows.entry(voxel).or_insert(0.0) += remaining - level;
+    }
+
+    for (voxel, flow) in flows {
+        let level = view.level(voxel) + flow;
+        view.set_level(voxel, if level < settings.min_level { 0.0 } else { level });
+
+        if flow.abs() >= settings.settled_flow {
+            active.voxels.insert(voxel);
+            for offset in NEIGHBOURS.iter() {
+                active
+                    .voxels
+                    .insert([voxel[0] + offset[0], voxel[1] + offset[1], voxel[2] + offset[2]]);
+            }
+        }
+    }
+}
+
+/// Wakes the water in chunks whose terrain changed, whether by an edit, a
+/// network update or anything else. Runs after the edit stages so it sees
+/// their changes.
+fn wake_water(
+    mut active: ResMut<ActiveWater>,
+    chunk_query: Query<&ChunkCoord, (Mutated<Chunk>, With<Water>)>,
+) {
+    for coord in chunk_query.iter() {
+        active.chunks.insert(*coord);
+    }
+}
+
+fn simulate_water(
+    time: Res<Time>,
+    settings: Res<WaterSettings>,
+    chunk_settings: Res<ChunkSettings>,
+    mut since_tick: Local<f32>,
+    mut active: ResMut<ActiveWater>,
+    mut water_query: Query<(&ChunkCoord, &Chunk, &mut Water)>,
+) {
+    *since_tick += time.delta_seconds();
+    if *since_tick < settings.tick {
+        return;
+    }
+    *since_tick = 0.0;
+
+    if active.voxels.is_empty() && active.chunks.is_empty() {
+        return;
+    }
+
+    let mut view = WaterView {
+        chunk_settings: &chunk_settings,
+        chunks: water_query
+            .iter_mut()
+            .map(|(coord, chunk, water)| (*coord, (chunk, water)))
+            .collect(),
+    };
+    step_water(&mut view, &settings, &mut active);
+}
+
+/// Pours water onto the terrain under the cursor while L is held.
+fn pour_water(
+    time: Res<Time>,
+    keyboard_input: Res<Input<KeyCode>>,
+    settings: Res<WaterSettings>,
+    chunk_settings: Res<ChunkSettings>,
+    terrain_cursor: Res<TerrainCursor>,
+    mut active: ResMut<ActiveWater>,
+    mut water_query: Query<(&ChunkCoord, &mut Water)>,
+) {
+    if !keyboard_input.pressed(KeyCode::L) {
+        return;
+    }
+
+    let hit = match terrain_cursor.hit.as_ref() {
+        Some(hit) => hit,
+        None => return,
+    };
+
+    let amount = settings.pour_rate * time.delta_seconds();
+    let voxel = nearest_voxel(hit.position + hit.normal);
+    active.voxels.insert(voxel);
+    for (coord, [x, y, z]) in voxel_locations(&chunk_settings, voxel) {
+        for (water_coord, mut water) in water_query.iter_mut() {
+            if *water_coord == coord {
+                water.levels[x][y][z] += amount;
+            }
+        }
+    }
+}
+
+/// Meshes changed water with the terrain's extractor into a transparent
+/// child of the chunk.
+fn mesh_water(
+    commands: &mut Commands,
+    chunk_settings: Res<ChunkSettings>,
+    water_assets: Res<WaterAssets>,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut water_query: Query<(Entity, &mut Water), Mutated<Water>>,
+    mesh_query: Query<&Handle<Mesh>>,
+) {
+    for (entity, mut water) in water_query.iter_mut() {
+        let (v_pos, normals, _, _, indices) = generate_mesh(&chunk_settings, &water.as_chunk(&chunk_settings));
+        if water.surface.is_none() && indices.is_empty() {
+            continue;
+        }
+
+        let uvs = vec![[0.0, 0.0]; v_pos.len()];
+        let mut mesh = Mesh::new(PrimitiveTopology::TriangleList);
+        mesh.set_attribute(Mesh::ATTRIBUTE_POSITION, VertexAttributeValues::Float3(v_pos));
+        mesh.set_attribute(Mesh::ATTRIBUTE_NORMAL, VertexAttributeValues::Float3(normals));
+        mesh.set_attribute(Mesh::ATTRIBUTE_UV_0, VertexAttributeValues::Float2(uvs));
+        mesh.set_indices(Some(Indices::U32(indices)));
+
+        if let Some(surface) = water.surface {
+            if let Ok(handle) = mesh_query.get(surface) {
+                if let Some(existing) = meshes.get_mut(handle) {
+                    *existing = mesh;
+                }
+            }
+            continue;
+        }
+
+        let surface = commands
+            .spawn(PbrBundle {
+                mesh: meshes.add(mesh),
+                material: water_assets.material.clone(),
+                visible: Visible {
+                    is_visible: true,
+                    is_transparent: true,
+                },
+                ..Default::default()
+            })
+            .current_entity()
+            .unwrap();
+        commands.push_children(entity, &[surface]);
+        water.surface = Some(surface);
+    }
+}
+
+pub struct WaterPlugin;
+
+impl Plugin for WaterPlugin {
+    fn build(&self, app: &mut AppBuilder) {
+        app.init_resource::<WaterSettings>()
+            .init_resource::<ActiveWater>()
+            .add_startup_system(setup_water.system())
+            .add_system(add_water.system())
+            .add_system(pour_water.system())
+            .add_system(simulate_water.system())
+            .add_system_to_stage(stage::POST_UPDATE, wake_water.system())
+            .add_system_to_stage(stage::POST_UPDATE, mesh_water.system());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chunk::test_util::settings;
+
+    fn open(chunk: &mut Chunk, x: std::ops::Range<usize>, y: std::ops::Range<usize>, z: std::ops::Range<usize>) {
+        for x in x {
+            for y in y.clone() {
+                for z in z.clone() {
+                    chunk.data[x][y][z] = 1.0;
+                }
+            }
+        }
+    }
+
+    fn basin_water(water: &Water) -> f32 {
+        let mut total = 0.0;
+        for x in 2..6 {
+            for y in 4..8 {
+                for z in 2..6 {
+                    total += water.levels[x][y][z];
+                }
+            }
+        }
+        total
+    }
+
+    fn run(chunk_settings: &ChunkSettings, chunk: &Chunk, water: &mut Water, active: &mut ActiveWater, steps: usize) {
+        let mut view = WaterView {
+            chunk_settings,
+            chunks: std::iter::once((ChunkCoord::new(0, 0, 0), (chunk, water))).collect(),
+        };
+        for _ in 0..steps {
+            step_water(&mut view, &WaterSettings::default(), active);
+        }
+    }
+
+    #[test]
+    fn basin_drains_once_a_channel_is_cut() {
+        let chunk_settings = settings();
+        let mut chunk = Chunk::new(&chunk_settings);
+        for plane in chunk.data.iter_mut() {
+            for row in plane.iter_mut() {
+                for density in row.iter_mut() {
+                    *density = -1.0;
+                }
+            }
+        }
+        // A basin and, behind a wall, a lower cave
+        open(&mut chunk, 2..6, 4..8, 2..6);
+        open(&mut chunk, 8..14, 0..6, 2..14);
+
+        let mut water = Water::new(&chunk_settings);
+        let mut active = ActiveWater::default();
+        for x in 2..6 {
+            for z in 2..6 {
+                for y in 4..6 {
+                    water.levels[x][y][z] = 1.0;
+                    active.voxels.insert([x as i32, y as i32, z as i32]);
+                }
+            }
+        }
+        let full = basin_water(&water);
+
+        run(&chunk_settings, &chunk, &mut water, &mut active, 50);
+        assert!((basin_water(&water) - full).abs() < 0.001);
+        assert!(active.voxels.is_empty(), "still water should settle");
+
+        open(&mut chunk, 6..8, 4..5, 3..4);
+        active.chunks.insert(ChunkCoord::new(0, 0, 0));
+        run(&chunk_settings, &chunk, &mut water, &mut active, 3000);
+        assert!(basin_water(&water) < full * 0.2);
+
+        let cave: f32 = water.levels[8..14]
+            .iter()
+            .flat_map(|plane| plane[0..6].iter())
+            .flat_map(|row| row[2..14].iter())
+            .sum();
+        assert!(cave > full * 0.5);
+    }
+}